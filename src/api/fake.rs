@@ -0,0 +1,178 @@
+use super::FloqApi;
+use crate::{
+    error::FloqError,
+    project::{Customer, Project},
+    timestamp::history::{ProjectTimestamp, Timestamp},
+    user::Employee,
+};
+
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{Datelike, Duration, NaiveDate};
+
+/// Time stamped on a project in the fake, one for each call to `add_timestamp`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimeEntry {
+    pub project_id: String,
+    pub date: NaiveDate,
+    pub time: Duration,
+}
+
+/// An in-memory Floq with a single employee, so that commands can be run without network access.
+pub struct FakeFloqApi {
+    employee: Employee,
+    projects: Vec<Project>,
+    time_entries: Mutex<Vec<TimeEntry>>,
+}
+
+impl FakeFloqApi {
+    pub fn new(employee: Employee) -> Self {
+        FakeFloqApi {
+            employee,
+            projects: vec![],
+            time_entries: Mutex::new(vec![]),
+        }
+    }
+
+    /// A fake where Ola Nordmann, with id 1, is logged in.
+    pub fn ola() -> Self {
+        FakeFloqApi::new(Employee {
+            id: 1,
+            email: "ola@blank.no".to_string(),
+            name: "Ola Nordmann".to_string(),
+        })
+    }
+
+    pub fn with_project(mut self, id: &str, name: &str, customer_name: &str) -> Self {
+        self.projects.push(Project {
+            id: id.to_string(),
+            name: name.to_string(),
+            active: true,
+            customer: Customer {
+                id: customer_name.to_lowercase(),
+                name: customer_name.to_string(),
+            },
+        });
+        self
+    }
+
+    pub fn with_time_entry(self, project_id: &str, date: NaiveDate, time: Duration) -> Self {
+        self.time_entries.lock().unwrap().push(TimeEntry {
+            project_id: project_id.to_string(),
+            date,
+            time,
+        });
+        self
+    }
+
+    /// Every time entry, in the order they were added.
+    pub fn time_entries(&self) -> Vec<TimeEntry> {
+        self.time_entries.lock().unwrap().clone()
+    }
+
+    fn project(&self, project_id: &str) -> Option<&Project> {
+        self.projects.iter().find(|p| p.id == project_id)
+    }
+
+    fn time_on(&self, project_id: &str, date: &NaiveDate) -> Duration {
+        self.time_entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|e| e.project_id == project_id && e.date == *date)
+            .fold(Duration::zero(), |sum, e| sum + e.time)
+    }
+}
+
+#[async_trait(?Send)]
+impl FloqApi for FakeFloqApi {
+    async fn get_projects(&self) -> Result<Vec<Project>> {
+        Ok(self.projects.clone())
+    }
+
+    async fn get_timestamped_projects_for_employee(
+        &self,
+        date: NaiveDate,
+        weeks: u32,
+    ) -> Result<Vec<Project>> {
+        let lower = date - Duration::weeks(weeks as i64);
+        let upper = date + Duration::days(6 - date.weekday().num_days_from_monday() as i64);
+        let entries = self.time_entries.lock().unwrap();
+
+        Ok(self
+            .projects
+            .iter()
+            .filter(|p| {
+                entries
+                    .iter()
+                    .any(|e| e.project_id == p.id && lower <= e.date && e.date <= upper)
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn get_timestamp_on_project_for_date(
+        &self,
+        project_id: &str,
+        date: &NaiveDate,
+    ) -> Result<Duration> {
+        Ok(self.time_on(project_id, date))
+    }
+
+    async fn get_timestamps_for_date(&self, date: NaiveDate) -> Result<Vec<ProjectTimestamp>> {
+        Ok(self
+            .projects
+            .iter()
+            .map(|p| ProjectTimestamp {
+                project_id: p.id.clone(),
+                project_name: p.name.clone(),
+                customer_name: p.customer.name.clone(),
+                timestamp: Timestamp {
+                    date,
+                    time: self.time_on(&p.id, &date),
+                },
+            })
+            .filter(|pt| !pt.timestamp.is_time_zero())
+            .collect())
+    }
+
+    async fn get_timestamps_for_period(
+        &self,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> Result<Vec<ProjectTimestamp>> {
+        let mut timestamps = vec![];
+        for date in from.iter_days().take_while(|d| d <= &to) {
+            timestamps.extend(self.get_timestamps_for_date(date).await?);
+        }
+
+        Ok(timestamps)
+    }
+
+    async fn add_timestamp(
+        &self,
+        project_id: &str,
+        date: &NaiveDate,
+        time: Duration,
+    ) -> Result<()> {
+        if self.project(project_id).is_none() {
+            return Err(anyhow!(FloqError::Validation(format!(
+                "Fant ikke prosjektet {}",
+                project_id
+            ))));
+        }
+
+        self.time_entries.lock().unwrap().push(TimeEntry {
+            project_id: project_id.to_string(),
+            date: *date,
+            time,
+        });
+        Ok(())
+    }
+
+    async fn get_logged_in_employee(&self) -> Result<Employee> {
+        Ok(self.employee.clone())
+    }
+}
@@ -0,0 +1,98 @@
+use crate::{
+    http_client::HttpClient, project::Project, timestamp::history::ProjectTimestamp, user::Employee,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{Duration, NaiveDate};
+
+pub mod fake;
+pub mod passthrough;
+
+/// The parts of the Floq API used by the subcommands, implemented by [`HttpClient`] and by the
+/// in-memory [`fake::FakeFloqApi`].
+#[async_trait(?Send)]
+pub trait FloqApi {
+    /// All projects in Floq, including the inactive ones.
+    async fn get_projects(&self) -> Result<Vec<Project>>;
+
+    /// Projects stamped on in the given number of weeks before `date`, and the rest of its week.
+    async fn get_timestamped_projects_for_employee(
+        &self,
+        date: NaiveDate,
+        weeks: u32,
+    ) -> Result<Vec<Project>>;
+
+    /// Time stamped on the project on the date.
+    async fn get_timestamp_on_project_for_date(
+        &self,
+        project_id: &str,
+        date: &NaiveDate,
+    ) -> Result<Duration>;
+
+    /// Time stamped on each project on the date, leaving out projects without any time.
+    async fn get_timestamps_for_date(&self, date: NaiveDate) -> Result<Vec<ProjectTimestamp>>;
+
+    /// Time stamped on each project for every day from `from` to `to`, both inclusive.
+    async fn get_timestamps_for_period(
+        &self,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> Result<Vec<ProjectTimestamp>>;
+
+    /// Adds `time` to what's already stamped on the project on the date, negative time removes
+    /// time.
+    async fn add_timestamp(&self, project_id: &str, date: &NaiveDate, time: Duration)
+        -> Result<()>;
+
+    /// The employee the API is used as.
+    async fn get_logged_in_employee(&self) -> Result<Employee>;
+}
+
+#[async_trait(?Send)]
+impl FloqApi for HttpClient {
+    async fn get_projects(&self) -> Result<Vec<Project>> {
+        HttpClient::get_projects(self).await
+    }
+
+    async fn get_timestamped_projects_for_employee(
+        &self,
+        date: NaiveDate,
+        weeks: u32,
+    ) -> Result<Vec<Project>> {
+        HttpClient::get_timestamped_projects_for_employee(self, date, weeks).await
+    }
+
+    async fn get_timestamp_on_project_for_date(
+        &self,
+        project_id: &str,
+        date: &NaiveDate,
+    ) -> Result<Duration> {
+        HttpClient::get_timestamp_on_project_for_date(self, project_id, date).await
+    }
+
+    async fn get_timestamps_for_date(&self, date: NaiveDate) -> Result<Vec<ProjectTimestamp>> {
+        HttpClient::get_timestamps_for_date(self, date).await
+    }
+
+    async fn get_timestamps_for_period(
+        &self,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> Result<Vec<ProjectTimestamp>> {
+        HttpClient::get_timestamps_for_period(self, from, to).await
+    }
+
+    async fn add_timestamp(
+        &self,
+        project_id: &str,
+        date: &NaiveDate,
+        time: Duration,
+    ) -> Result<()> {
+        HttpClient::add_timestamp(self, project_id, date, time).await
+    }
+
+    async fn get_logged_in_employee(&self) -> Result<Employee> {
+        HttpClient::get_logged_in_employee(self).await
+    }
+}
@@ -0,0 +1,238 @@
+use crate::{
+    cmd::{self, Subcommand},
+    error::FloqError,
+    http_client::HttpClient,
+    user,
+};
+
+use std::io::{self, Read, Write};
+
+use anyhow::{anyhow, Context, Result};
+use async_std::fs;
+use async_trait::async_trait;
+use clap::{App, Arg, ArgMatches};
+use serde_json::Value;
+use surf::{http::Method, Url};
+
+const SUBCOMMAND_NAME: &str = "api";
+
+const METHODS: [&str; 7] = ["GET", "POST", "PATCH", "PUT", "DELETE", "HEAD", "OPTIONS"];
+
+pub fn subcommand_app<'help>() -> App<'help> {
+    App::new(SUBCOMMAND_NAME)
+        .about("Send en forespørsel rett til Floq API-et og skriv ut svaret")
+        .arg(
+            Arg::new("metode")
+                .about("HTTP-metoden som skal brukes")
+                .required(true)
+                .possible_values(&METHODS)
+                .case_insensitive(true)
+                .index(1),
+        )
+        .arg(
+            Arg::new("sti")
+                .about("Stien i API-et, med eller uten spørring.\nF.eks. \"time_entry?date=eq.2021-03-01\" eller \"rpc/who_am_i\"")
+                .required(true)
+                .index(2),
+        )
+        .arg(
+            Arg::new("data")
+                .long("data")
+                .short('d')
+                .takes_value(true)
+                .about("JSON som sendes som innhold, eller @fil for å lese det fra en fil og @- fra stdin.\nF.eks. '{\"employee_id\": 1}'"),
+        )
+        .arg(
+            Arg::new("param")
+                .long("param")
+                .short('p')
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .about("Parameter som legges til spørringen, kan gjentas.\nF.eks. \"--param select=id,name --param active=is.true\""),
+        )
+        .arg(
+            Arg::new("header")
+                .long("header")
+                .short('H')
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .about("Header som sendes med, kan gjentas.\nF.eks. \"--header 'Prefer: return=representation'\""),
+        )
+        .arg(
+            Arg::new("rå")
+                .long("rå")
+                .about("Skriv ut svaret slik det kom fra Floq, i stedet for formatert JSON"),
+        )
+}
+
+pub fn subcommand<T: Write + Send>() -> Box<dyn Subcommand<T>> {
+    Box::new(ApiSubcommand)
+}
+
+struct ApiSubcommand;
+
+#[async_trait(?Send)]
+impl<T: Write + Send> Subcommand<T> for ApiSubcommand {
+    fn matches(&self, matches: &ArgMatches) -> bool {
+        matches.subcommand_name() == Some(SUBCOMMAND_NAME)
+    }
+
+    async fn execute(&self, matches: &ArgMatches, out: &mut T) -> Result<()> {
+        let method: Method = matches
+            .value_of("metode")
+            .unwrap()
+            .to_uppercase()
+            .parse()
+            .map_err(|_| anyhow!("Ukjent HTTP-metode"))?;
+        let params = values_split_by(matches, "param", '=')?;
+        let headers = values_split_by(matches, "header", ':')?;
+        let data = match matches.value_of("data") {
+            Some(data) => Some(read_data(data).await?),
+            None => None,
+        };
+
+        let (user, preferences) = user::load_user_and_preferences(matches, out).await?;
+        let client =
+            HttpClient::from_user(&user)?.with_retry(cmd::retry_policy(matches, &preferences));
+        let path = api_path(
+            &client.api_domain,
+            matches.value_of("sti").unwrap(),
+            &params,
+        )?;
+
+        let mut response = client
+            .send(|| {
+                let mut request = client.request(method, &path);
+                for (name, value) in &headers {
+                    request = request.header(*name, *value);
+                }
+                match &data {
+                    Some(data) => request.body(data.as_str()),
+                    None => request,
+                }
+            })
+            .await
+            .with_context(|| format!("{} /{} feilet", method, path))?;
+
+        let body = response
+            .body_bytes()
+            .await
+            .map_err(|e| anyhow!(e))
+            .context(FloqError::MalformedBody)?;
+        if body.is_empty() {
+            return Ok(());
+        }
+
+        match serde_json::from_slice::<Value>(&body) {
+            Ok(json) if !matches.is_present("rå") => {
+                writeln!(out, "{}", serde_json::to_string_pretty(&json)?)?
+            }
+            _ => {
+                out.write_all(&body)?;
+                if !body.ends_with(b"\n") {
+                    writeln!(out)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The values of `arg` split in two at the first `separator`, with whitespace trimmed.
+fn values_split_by<'a>(
+    matches: &'a ArgMatches,
+    arg: &str,
+    separator: char,
+) -> Result<Vec<(&'a str, &'a str)>> {
+    matches
+        .values_of(arg)
+        .into_iter()
+        .flatten()
+        .map(|value| {
+            value
+                .split_once(separator)
+                .map(|(name, value)| (name.trim(), value.trim()))
+                .filter(|(name, _)| !name.is_empty())
+                .ok_or_else(|| {
+                    anyhow!(FloqError::Validation(format!(
+                        "--{} må være på formen navn{}verdi, fikk \"{}\"",
+                        arg, separator, value
+                    )))
+                })
+        })
+        .collect()
+}
+
+/// The JSON in `data`, or in the file it points to with `@`, where `@-` is stdin.
+async fn read_data(data: &str) -> Result<String> {
+    let json = match data.strip_prefix('@') {
+        Some("-") => {
+            let mut json = String::new();
+            io::stdin()
+                .read_to_string(&mut json)
+                .with_context(|| "Klarte ikke å lese innholdet fra stdin")?;
+            json
+        }
+        Some(path) => fs::read_to_string(path)
+            .await
+            .with_context(|| FloqError::Validation(format!("Klarte ikke å lese {}", path)))?,
+        None => data.to_string(),
+    };
+
+    serde_json::from_str::<Value>(&json)
+        .with_context(|| FloqError::Validation("--data må være gyldig JSON".to_string()))?;
+    Ok(json)
+}
+
+/// `path` relative to the API domain, with `params` added to its query. Paths leading anywhere
+/// but the API domain are rejected, as the access token is sent along.
+fn api_path(api_domain: &str, path: &str, params: &[(&str, &str)]) -> Result<String> {
+    let mut path = path.trim_start_matches('/').to_string();
+    if !params.is_empty() {
+        path.push(if path.contains('?') { '&' } else { '?' });
+        path.push_str(&serde_urlencoded::to_string(params)?);
+    }
+
+    let base = Url::parse(&format!("{}/", api_domain.trim_end_matches('/')))
+        .with_context(|| format!("Ugyldig adresse {}", api_domain))?;
+    match base.join(&path) {
+        Ok(url) if url.origin() == base.origin() && url.path().starts_with(base.path()) => Ok(path),
+        _ => Err(anyhow!(FloqError::Validation(format!(
+            "{} er ikke en sti i Floq API-et",
+            path
+        )))),
+    }
+}
+
+#[cfg(test)]
+demonstrate::demonstrate! {
+    describe "api_path" {
+        use super::*;
+
+        it "adds the params to the query" {
+            let path = api_path(
+                "https://api-blank.floq.no",
+                "/time_entry?employee=eq.1",
+                &[("date", "gte.2021-03-01"), ("select", "date,minutes")],
+            )
+            .unwrap();
+
+            assert_eq!(
+                path,
+                "time_entry?employee=eq.1&date=gte.2021-03-01&select=date%2Cminutes"
+            );
+        }
+
+        it "rejects paths leaving the API" {
+            for path in &["https://example.com/time_entry", "http:example.com", "../x"] {
+                let error = api_path("https://floq.no/api", path, &[]).unwrap_err();
+
+                assert!(matches!(
+                    error.downcast_ref::<FloqError>(),
+                    Some(FloqError::Validation(_))
+                ));
+            }
+        }
+    }
+}
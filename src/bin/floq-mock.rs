@@ -0,0 +1,563 @@
+//! A local stand-in for Floq, serving the PostgREST endpoints and the login used by the CLI from a
+//! JSON fixture, so that the CLI can be developed and demoed without touching a real environment.
+//!
+//! Changes, such as stamped hours, are only kept in memory while the mock is running.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    sync::Mutex,
+};
+
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64, Engine};
+use chrono::{DateTime, Duration, NaiveDate, SecondsFormat, Utc};
+use clap::{App, Arg};
+use rand::{distributions::Alphanumeric, Rng};
+use rouille::{input::json_input, Request, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+const DEFAULT_FIXTURE: &str = include_str!("../../fixtures/floq-mock.json");
+const DEFAULT_OPENAPI: &str = include_str!("../../fixtures/floq-mock-openapi.json");
+/// Threads serving requests, see `main`.
+const POOL_SIZE: usize = 64;
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Employee {
+    id: u16,
+    email: String,
+    first_name: String,
+    last_name: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Project {
+    id: String,
+    name: String,
+    active: bool,
+    customer: Customer,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Customer {
+    id: String,
+    name: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct TimeEntry {
+    employee: u16,
+    project: String,
+    date: NaiveDate,
+    minutes: i64,
+}
+
+#[derive(Deserialize)]
+struct Fixture {
+    employee: Employee,
+    projects: Vec<Project>,
+    #[serde(default)]
+    time_entries: Vec<TimeEntry>,
+}
+
+#[derive(Deserialize)]
+struct RefreshTokenRequest {
+    refresh_token: String,
+}
+
+#[derive(Deserialize)]
+struct NewTimeEntry {
+    employee: u16,
+    project: String,
+    date: NaiveDate,
+    minutes: i64,
+}
+
+#[derive(Deserialize)]
+struct ProjectsForDateRequest {
+    employee_id: u16,
+    date: NaiveDate,
+}
+
+#[derive(Deserialize)]
+struct ProjectsInPeriodRequest {
+    employee_id: u16,
+    date_range: String,
+}
+
+struct Mock {
+    fixture: Fixture,
+    openapi: serde_json::Value,
+    token_lifetime: Duration,
+    /// Like PostgREST's `max-rows`, the most rows answered to a query.
+    max_rows: Option<usize>,
+    access_tokens: HashMap<String, DateTime<Utc>>,
+    refresh_tokens: HashSet<String>,
+}
+
+fn main() -> Result<()> {
+    let matches = App::new("floq-mock")
+        .about("Lokal etterligning av Floq for utvikling uten nettverk")
+        .version(VERSION)
+        .arg(
+            Arg::new("port")
+                .long("port")
+                .short('p')
+                .takes_value(true)
+                .default_value("8765")
+                .about("Porten mocken lytter på"),
+        )
+        .arg(
+            Arg::new("fixtur")
+                .long("fixtur")
+                .takes_value(true)
+                .about("JSON-fil med ansatt, prosjekter og timer, se fixtures/floq-mock.json"),
+        )
+        .arg(
+            Arg::new("token-levetid")
+                .long("token-levetid")
+                .takes_value(true)
+                .default_value("3600")
+                .about(
+                    "Antall sekunder access-tokenene er gyldige, sett lavt for å teste fornyelse",
+                ),
+        )
+        .arg(
+            Arg::new("maks-rader")
+                .long("maks-rader")
+                .takes_value(true)
+                .about("Det meste antallet rader i et svar, som max-rows i PostgREST"),
+        )
+        .arg(
+            Arg::new("openapi")
+                .long("openapi")
+                .takes_value(true)
+                .about("JSON-fil med OpenAPI-beskrivelsen som serveres på /, se fixtures/floq-mock-openapi.json"),
+        )
+        .get_matches();
+
+    let port: u16 = matches
+        .value_of("port")
+        .unwrap()
+        .parse()
+        .with_context(|| "Porten må være et tall, f.eks. 8765")?;
+    let token_lifetime: i64 = matches
+        .value_of("token-levetid")
+        .unwrap()
+        .parse()
+        .with_context(|| "Levetiden må være et antall sekunder, f.eks. 3600")?;
+    let max_rows: Option<usize> = matches
+        .value_of("maks-rader")
+        .map(str::parse)
+        .transpose()
+        .with_context(|| "Antallet rader må være et tall, f.eks. 100")?;
+    let fixture = match matches.value_of("fixtur") {
+        Some(path) => fs::read_to_string(path)
+            .with_context(|| format!("Klarte ikke å lese fixturen {}", path))?,
+        None => DEFAULT_FIXTURE.to_string(),
+    };
+    let fixture: Fixture =
+        serde_json::from_str(&fixture).with_context(|| "Fixturen er ikke gyldig")?;
+    let openapi = match matches.value_of("openapi") {
+        Some(path) => fs::read_to_string(path)
+            .with_context(|| format!("Klarte ikke å lese OpenAPI-beskrivelsen {}", path))?,
+        None => DEFAULT_OPENAPI.to_string(),
+    };
+    let openapi =
+        serde_json::from_str(&openapi).with_context(|| "OpenAPI-beskrivelsen er ikke gyldig")?;
+
+    let mock = Mutex::new(Mock {
+        fixture,
+        openapi,
+        token_lifetime: Duration::seconds(token_lifetime),
+        max_rows,
+        access_tokens: HashMap::new(),
+        refresh_tokens: HashSet::new(),
+    });
+
+    println!("floq-mock kjører på http://127.0.0.1:{}", port);
+    println!("Legg den til som en profil med:");
+    println!(
+        "floq bruker profiler legg-til mock --domene http://127.0.0.1:{0} --api-domene http://127.0.0.1:{0}",
+        port
+    );
+
+    let server = rouille::Server::new(("127.0.0.1", port), move |request| {
+        let response = mock.lock().unwrap().handle(request);
+        eprintln!(
+            "{} {} {}",
+            request.method(),
+            request.raw_url(),
+            response.status_code
+        );
+        response
+    })
+    .map_err(|e| anyhow!("Klarte ikke å lytte på port {}: {}", port, e))?;
+
+    // each keep-alive connection holds on to a thread while it's idle, so there must be plenty
+    // more threads than the connections floq keeps open, or new requests are left waiting
+    server.pool_size(POOL_SIZE).run();
+    Ok(())
+}
+
+impl Mock {
+    fn handle(&mut self, request: &Request) -> Response {
+        match (request.method(), request.url().as_str()) {
+            ("GET", "/login/oauth") => self.login(request),
+            ("POST", "/login/oauth/refresh") => self.refresh(request),
+            ("POST", "/login/oauth/revoke") => self.revoke(request),
+            // like PostgREST, the description of the API is served without a login
+            ("GET", "/") => Response::json(&self.openapi)
+                .with_unique_header("Content-Type", "application/openapi+json; charset=utf-8"),
+            (method, url) => {
+                if let Err(response) = self.authenticate(request) {
+                    return response;
+                }
+
+                match (method, url) {
+                    ("GET", "/projects") => Response::json(&self.fixture.projects),
+                    ("GET", "/time_entry") => self.get_time_entries(request),
+                    ("POST", "/time_entry") => self.add_time_entry(request),
+                    ("POST", "/rpc/who_am_i") => Response::json(&[&self.fixture.employee]),
+                    ("POST", "/rpc/projects_for_employee_for_date") => {
+                        self.projects_for_employee_for_date(request)
+                    }
+                    ("POST", "/rpc/projects_info_for_employee_in_period") => {
+                        self.projects_info_for_employee_in_period(request)
+                    }
+                    _ => postgrest_error(
+                        404,
+                        "PGRST202",
+                        &format!("Could not find {} {}", method, url),
+                        None,
+                    ),
+                }
+            }
+        }
+    }
+
+    /// Logs in right away, redirecting back to the CLI like Floq does after a login with Google.
+    fn login(&mut self, request: &Request) -> Response {
+        let to = match request.get_param("to") {
+            Some(to) => to,
+            None => return Response::text("Mangler parameteren to").with_status_code(400),
+        };
+
+        let (access_token, expiry_date) = self.issue_access_token();
+        let refresh_token = random_string();
+        self.refresh_tokens.insert(refresh_token.clone());
+
+        let query = serde_urlencoded::to_string([
+            ("access_token", access_token.as_str()),
+            ("refresh_token", refresh_token.as_str()),
+            ("expiry_date", expiry_date.as_str()),
+        ])
+        .unwrap();
+        Response::redirect_302(format!("{}?{}", to, query))
+    }
+
+    fn refresh(&mut self, request: &Request) -> Response {
+        let body: RefreshTokenRequest = match json_input(request) {
+            Ok(b) => b,
+            Err(e) => return Response::text(e.to_string()).with_status_code(400),
+        };
+        if !self.refresh_tokens.contains(&body.refresh_token) {
+            return Response::json(&json!({ "message": "Invalid refresh token" }))
+                .with_status_code(401);
+        }
+
+        let (access_token, expiry_date) = self.issue_access_token();
+        Response::json(&json!({
+            "access_token": access_token,
+            "expiry_date": expiry_date,
+        }))
+    }
+
+    fn revoke(&mut self, request: &Request) -> Response {
+        match json_input::<RefreshTokenRequest>(request) {
+            Ok(body) => {
+                self.refresh_tokens.remove(&body.refresh_token);
+                Response::empty_204()
+            }
+            Err(e) => Response::text(e.to_string()).with_status_code(400),
+        }
+    }
+
+    /// A JWT with the claims read by `floq bruker status`, which is not signed as it's never
+    /// verified by the CLI.
+    fn issue_access_token(&mut self) -> (String, String) {
+        let expires = Utc::now() + self.token_lifetime;
+        let header = BASE64.encode(r#"{"alg":"HS256","typ":"JWT"}"#);
+        let claims = BASE64.encode(
+            json!({
+                "exp": expires.timestamp(),
+                "email": self.fixture.employee.email,
+                "role": "employee",
+                "jti": random_string(),
+            })
+            .to_string(),
+        );
+        let access_token = format!("{}.{}.mock", header, claims);
+
+        self.access_tokens.insert(access_token.clone(), expires);
+        (
+            access_token,
+            expires.to_rfc3339_opts(SecondsFormat::Secs, true),
+        )
+    }
+
+    fn authenticate(&self, request: &Request) -> Result<(), Response> {
+        let access_token = request
+            .header("Authorization")
+            .and_then(|h| h.strip_prefix("Bearer "));
+
+        match access_token.and_then(|t| self.access_tokens.get(t)) {
+            Some(expires) if *expires > Utc::now() => Ok(()),
+            Some(_) => {
+                Err(Response::json(&json!({ "message": "JWT expired" })).with_status_code(401))
+            }
+            None => Err(Response::json(&json!({ "message": "JWT invalid" })).with_status_code(401)),
+        }
+    }
+
+    /// Supports the PostgREST filters `eq`, `neq`, `gt`, `gte`, `lt` and `lte` on the employee,
+    /// project and date, `offset` and `limit`, and embedding the project with its customer when
+    /// the select asks for it. The entries are always in the order they were added, and there's
+    /// no `Content-Range`, as tiny_http won't send it.
+    fn get_time_entries(&self, request: &Request) -> Response {
+        let params: Vec<(String, String)> =
+            serde_urlencoded::from_str(request.raw_query_string()).unwrap_or_default();
+        let embeds_project = params.iter().any(|(column, select)| {
+            column == "select" && (select.contains("project{") || select.contains("project("))
+        });
+        let mut offset = 0;
+        let mut limit = self.max_rows.unwrap_or(usize::MAX);
+        let mut filters = vec![];
+        for (column, value) in params {
+            match column.as_str() {
+                "select" | "order" => {}
+                "offset" | "limit" => {
+                    let n = match value.parse::<usize>() {
+                        Ok(n) => n,
+                        Err(_) => {
+                            return postgrest_error(
+                                400,
+                                "PGRST100",
+                                &format!("\"failed to parse {} ({})\"", column, value),
+                                None,
+                            )
+                        }
+                    };
+                    if column == "offset" {
+                        offset = n;
+                    } else {
+                        limit = limit.min(n);
+                    }
+                }
+                _ => filters.push((column, value)),
+            }
+        }
+
+        let mut entries = vec![];
+        for entry in &self.fixture.time_entries {
+            let mut included = true;
+            for (column, filter) in &filters {
+                match matches_filter(entry, column, filter) {
+                    Ok(m) => included &= m,
+                    Err(response) => return response,
+                }
+            }
+            if included {
+                entries.push(entry);
+            }
+        }
+        let entries: Vec<_> = entries.into_iter().skip(offset).take(limit).collect();
+
+        if embeds_project {
+            let entries: Vec<_> = entries
+                .into_iter()
+                .map(|entry| {
+                    let project = self.fixture.projects.iter().find(|p| p.id == entry.project);
+                    json!({
+                        "employee": entry.employee,
+                        "date": entry.date,
+                        "minutes": entry.minutes,
+                        "project": project,
+                    })
+                })
+                .collect();
+            return Response::json(&entries);
+        }
+
+        Response::json(&entries)
+    }
+
+    fn add_time_entry(&mut self, request: &Request) -> Response {
+        let body: NewTimeEntry = match json_input(request) {
+            Ok(b) => b,
+            Err(e) => return postgrest_error(400, "PGRST102", &e.to_string(), None),
+        };
+        if !self.fixture.projects.iter().any(|p| p.id == body.project) {
+            return postgrest_error(
+                409,
+                "23503",
+                "insert or update on table \"time_entry\" violates foreign key constraint \"time_entry_project_fkey\"",
+                Some(format!(
+                    "Key (project)=({}) is not present in table \"projects\".",
+                    body.project
+                )),
+            );
+        }
+
+        self.fixture.time_entries.push(TimeEntry {
+            employee: body.employee,
+            project: body.project,
+            date: body.date,
+            minutes: body.minutes,
+        });
+        Response::text("").with_status_code(201)
+    }
+
+    fn projects_for_employee_for_date(&self, request: &Request) -> Response {
+        let body: ProjectsForDateRequest = match json_input(request) {
+            Ok(b) => b,
+            Err(e) => return postgrest_error(400, "PGRST102", &e.to_string(), None),
+        };
+
+        let projects: Vec<_> = self
+            .fixture
+            .projects
+            .iter()
+            .filter_map(|p| {
+                let minutes = self.minutes(body.employee_id, &p.id, |d| d == body.date)?;
+                Some(json!({
+                    "id": p.id,
+                    "project": p.name,
+                    "customer": p.customer.name,
+                    "minutes": minutes,
+                }))
+            })
+            .collect();
+        Response::json(&projects)
+    }
+
+    fn projects_info_for_employee_in_period(&self, request: &Request) -> Response {
+        let body: ProjectsInPeriodRequest = match json_input(request) {
+            Ok(b) => b,
+            Err(e) => return postgrest_error(400, "PGRST102", &e.to_string(), None),
+        };
+        let (lower, upper) = match parse_date_range(&body.date_range) {
+            Some(range) => range,
+            None => {
+                return postgrest_error(
+                    400,
+                    "22007",
+                    &format!("malformed range literal: \"{}\"", body.date_range),
+                    None,
+                )
+            }
+        };
+
+        let projects: Vec<_> = self
+            .fixture
+            .projects
+            .iter()
+            .filter(|p| {
+                self.minutes(body.employee_id, &p.id, |d| lower <= d && d <= upper)
+                    .is_some()
+            })
+            .map(|p| {
+                json!({
+                    "id": p.id,
+                    "name": p.name,
+                    "active": p.active,
+                    "customer_id": p.customer.id,
+                    "customer_name": p.customer.name,
+                })
+            })
+            .collect();
+        Response::json(&projects)
+    }
+
+    /// Minutes stamped by the employee on the project on the dates, if any entries.
+    fn minutes<F>(&self, employee: u16, project: &str, on_date: F) -> Option<i64>
+    where
+        F: Fn(NaiveDate) -> bool,
+    {
+        self.fixture
+            .time_entries
+            .iter()
+            .filter(|e| e.employee == employee && e.project == project && on_date(e.date))
+            .map(|e| e.minutes)
+            .reduce(|sum, m| sum + m)
+    }
+}
+
+fn matches_filter(entry: &TimeEntry, column: &str, filter: &str) -> Result<bool, Response> {
+    let invalid = || {
+        postgrest_error(
+            400,
+            "PGRST100",
+            &format!("\"failed to parse filter ({})\"", filter),
+            None,
+        )
+    };
+    let (operator, value) = filter.split_once('.').ok_or_else(invalid)?;
+
+    let ordering = match column {
+        "employee" => entry
+            .employee
+            .cmp(&value.parse::<u16>().map_err(|_| invalid())?),
+        "project" => entry.project.as_str().cmp(value),
+        "date" => entry
+            .date
+            .cmp(&value.parse::<NaiveDate>().map_err(|_| invalid())?),
+        _ => {
+            return Err(postgrest_error(
+                400,
+                "42703",
+                &format!("column time_entry.{} does not exist", column),
+                None,
+            ))
+        }
+    };
+
+    match operator {
+        "eq" => Ok(ordering.is_eq()),
+        "neq" => Ok(ordering.is_ne()),
+        "gt" => Ok(ordering.is_gt()),
+        "gte" => Ok(ordering.is_ge()),
+        "lt" => Ok(ordering.is_lt()),
+        "lte" => Ok(ordering.is_le()),
+        _ => Err(invalid()),
+    }
+}
+
+/// Reads a range like `(2021-03-01, 2021-03-07)`, where both ends are treated as inclusive.
+fn parse_date_range(range: &str) -> Option<(NaiveDate, NaiveDate)> {
+    let (lower, upper) = range
+        .trim_matches(|c| matches!(c, '(' | ')' | '[' | ']'))
+        .split_once(',')?;
+
+    Some((lower.trim().parse().ok()?, upper.trim().parse().ok()?))
+}
+
+/// An error response in the format used by PostgREST.
+fn postgrest_error(status: u16, code: &str, message: &str, details: Option<String>) -> Response {
+    Response::json(&json!({
+        "code": code,
+        "message": message,
+        "details": details,
+        "hint": null,
+    }))
+    .with_status_code(status)
+}
+
+fn random_string() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .collect()
+}
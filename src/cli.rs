@@ -0,0 +1,83 @@
+use crate::{api, cmd::Subcommand, diagnose, project, timestamp, trace, user};
+
+use std::{env, io::Write};
+
+use anyhow::Result;
+use clap::{App, AppSettings, Arg, ArgMatches};
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The `floq` command line app with all its subcommands.
+pub fn app<'help>() -> App<'help> {
+    App::new("floq")
+        .about("Floq i din lokale terminal")
+        .version(VERSION)
+        .author("Rust-gjengen")
+        .setting(AppSettings::ArgRequiredElseHelp)
+        .arg(
+            Arg::new("profil")
+                .long("profil")
+                .takes_value(true)
+                .env("FLOQ_PROFILE")
+                .global(true)
+                .about(
+                    "Profilen (Floq-miljøet) som skal brukes, se \"floq bruker profiler liste\"",
+                ),
+        )
+        .arg(
+            Arg::new("ikke-interaktiv")
+                .long("ikke-interaktiv")
+                .global(true)
+                .about(
+                    "Start aldri innlogging, men avslutt med kode 3 hvis innlogging trengs.\nSkjer automatisk når stdin ikke er en terminal, f.eks. i skript",
+                ),
+        )
+        .arg(
+            Arg::new("ingen-retry")
+                .long("ingen-retry")
+                .global(true)
+                .about("Send aldri en forespørsel på nytt når nettverket eller Floq feiler"),
+        )
+        .arg(
+            Arg::new("verbose")
+                .long("verbose")
+                .short('v')
+                .global(true)
+                .multiple_occurrences(true)
+                .about(
+                    "Skriv ut forespørslene til Floq på stderr, uten tokens. Gjenta for mer:\n-v for metode, adresse, status og tid\n-vv også for headere og innhold\n-vvv også for innholdet i svarene\nKan også settes med FLOQ_LOG=1, 2 eller 3",
+                ),
+        )
+        .subcommand(user::subcommand_app().display_order(1))
+        .subcommand(project::subcommand_app().display_order(2))
+        .subcommand(timestamp::subcommand_app().display_order(3))
+        .subcommand(timestamp::history::subcommand_app().display_order(4))
+        .subcommand(user::settings::subcommand_app().display_order(5))
+        .subcommand(api::passthrough::subcommand_app().display_order(6))
+        .subcommand(diagnose::subcommand_app().display_order(7))
+}
+
+/// The subcommands of [`app`], each handling the matches of one of its subcommands.
+pub fn subcommands<T: Write + Send>() -> [Box<dyn Subcommand<T>>; 7] {
+    [
+        user::subcommand(),
+        project::subcommand(),
+        timestamp::subcommand(),
+        timestamp::history::subcommand(),
+        user::settings::subcommand(),
+        api::passthrough::subcommand(),
+        diagnose::subcommand(),
+    ]
+}
+
+/// How much to log, from `-v` after any of the subcommands and from `FLOQ_LOG`.
+pub fn log_level(matches: &ArgMatches) -> Result<trace::Level> {
+    let mut occurrences = 0;
+    let mut current = Some(matches);
+    while let Some(m) = current {
+        occurrences = occurrences.max(m.occurrences_of("verbose"));
+        current = m.subcommand().map(|(_, sub_matches)| sub_matches);
+    }
+
+    trace::Level::from_args(occurrences, env::var(trace::LOG_ENV).ok().as_deref())
+}
@@ -1,4 +1,9 @@
-use std::io::Write;
+use crate::{http_client::RetryPolicy, user::Preferences};
+
+use std::{
+    io::{self, IsTerminal, Write},
+    time::Duration,
+};
 
 use anyhow::Result;
 use async_trait::async_trait;
@@ -10,3 +15,21 @@ pub trait Subcommand<T: Write + Send> {
 
     async fn execute(&self, matches: &ArgMatches, out: &mut T) -> Result<()>;
 }
+
+/// Whether the user can be asked to log in, which is not the case with `--ikke-interaktiv` or when
+/// stdin is not a terminal.
+pub fn is_interactive(matches: &ArgMatches) -> bool {
+    !matches.is_present("ikke-interaktiv") && io::stdin().is_terminal()
+}
+
+/// How requests to Floq are retried, from the preferences unless `--ingen-retry` is given.
+pub fn retry_policy(matches: &ArgMatches, preferences: &Preferences) -> RetryPolicy {
+    if matches.is_present("ingen-retry") {
+        RetryPolicy::none()
+    } else {
+        RetryPolicy {
+            retries: preferences.retries,
+            delay: Duration::from_millis(preferences.retry_delay_ms),
+        }
+    }
+}
@@ -0,0 +1,312 @@
+//! Compares the parts of the Floq API that floq depends on with the OpenAPI description PostgREST
+//! publishes at the API root.
+
+use crate::{
+    error::FloqError,
+    http_client::{HandleMalformedBody, HttpClient},
+    print::TableMaker,
+    user::Preferences,
+};
+
+use std::{fmt::Display, io::Write};
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+use surf::Response;
+
+/// How floq reads or writes a value, and thus which types in the API it's compatible with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Integer,
+    Boolean,
+    /// Any string, e.g. text or a range.
+    Text,
+    /// A string with a date, `YYYY-MM-DD`.
+    Date,
+}
+
+impl Kind {
+    fn accepts(&self, property: &Value) -> bool {
+        let format = property["format"].as_str();
+        match (self, property["type"].as_str()) {
+            (Kind::Integer, Some("integer")) => true,
+            (Kind::Boolean, Some("boolean")) => true,
+            (Kind::Text, Some("string")) => true,
+            (Kind::Date, Some("string")) => format == Some("date"),
+            _ => false,
+        }
+    }
+}
+
+impl Display for Kind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Kind::Integer => write!(f, "et heltall"),
+            Kind::Boolean => write!(f, "en sannhetsverdi"),
+            Kind::Text => write!(f, "tekst"),
+            Kind::Date => write!(f, "en dato"),
+        }
+    }
+}
+
+/// A part of the Floq API that floq depends on.
+enum Dependency {
+    /// An endpoint, called with the method.
+    Endpoint(&'static str, &'static str),
+    /// A column of a table.
+    Column(&'static str, &'static str, Kind),
+    /// A column referencing another table, which floq embeds in its queries.
+    ForeignKey(&'static str, &'static str, &'static str),
+    /// An RPC function and the parameters it's called with, which must include every parameter
+    /// it requires.
+    Parameters(&'static str, &'static [(&'static str, Kind)]),
+}
+
+/// Everything floq uses, see the requests in `project`, `timestamp::http` and `user::http`. The
+/// results of RPC functions are not described by PostgREST, and can't be checked.
+const DEPENDENCIES: [Dependency; 20] = [
+    Dependency::Endpoint("/projects", "get"),
+    Dependency::Column("projects", "id", Kind::Text),
+    Dependency::Column("projects", "name", Kind::Text),
+    Dependency::Column("projects", "active", Kind::Boolean),
+    Dependency::Column("projects", "customer", Kind::Text),
+    Dependency::ForeignKey("projects", "customer", "customers"),
+    Dependency::Column("customers", "id", Kind::Text),
+    Dependency::Column("customers", "name", Kind::Text),
+    Dependency::Endpoint("/time_entry", "get"),
+    Dependency::Endpoint("/time_entry", "post"),
+    Dependency::Column("time_entry", "id", Kind::Integer),
+    Dependency::Column("time_entry", "employee", Kind::Integer),
+    Dependency::Column("time_entry", "creator", Kind::Integer),
+    Dependency::Column("time_entry", "project", Kind::Text),
+    Dependency::ForeignKey("time_entry", "project", "projects"),
+    Dependency::Column("time_entry", "date", Kind::Date),
+    Dependency::Column("time_entry", "minutes", Kind::Integer),
+    Dependency::Parameters("who_am_i", &[]),
+    Dependency::Parameters(
+        "projects_for_employee_for_date",
+        &[("employee_id", Kind::Integer), ("date", Kind::Date)],
+    ),
+    Dependency::Parameters(
+        "projects_info_for_employee_in_period",
+        &[("employee_id", Kind::Integer), ("date_range", Kind::Text)],
+    ),
+];
+
+/// The result of checking a [`Dependency`], with what's wrong if it's no longer met.
+struct Check {
+    dependency: String,
+    problem: Option<String>,
+}
+
+impl Dependency {
+    fn check(&self, openapi: &Value) -> Check {
+        let (dependency, problem) = match self {
+            Dependency::Endpoint(path, method) => (
+                format!("{} {}", method.to_uppercase(), path),
+                openapi["paths"][*path][*method]
+                    .is_null()
+                    .then(|| "Finnes ikke".to_string()),
+            ),
+            Dependency::Column(table, column, kind) => (
+                format!("{}.{}", table, column),
+                column_problem(openapi, table, column, |property| {
+                    (!kind.accepts(property))
+                        .then(|| format!("Er {}, floq forventer {}", type_of(property), kind))
+                }),
+            ),
+            Dependency::ForeignKey(table, column, references) => (
+                format!("{}.{} -> {}", table, column, references),
+                column_problem(openapi, table, column, |property| {
+                    let marker = format!("<fk table='{}'", references);
+                    (!property["description"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .contains(&marker))
+                    .then(|| format!("Er ikke lenger en fremmednøkkel til {}", references))
+                }),
+            ),
+            Dependency::Parameters(function, parameters) => {
+                let names: Vec<&str> = parameters.iter().map(|(name, _)| *name).collect();
+                (
+                    format!("rpc/{}({})", function, names.join(", ")),
+                    parameters_problem(openapi, function, parameters),
+                )
+            }
+        };
+
+        Check {
+            dependency,
+            problem,
+        }
+    }
+}
+
+fn column_problem<F>(openapi: &Value, table: &str, column: &str, check: F) -> Option<String>
+where
+    F: Fn(&Value) -> Option<String>,
+{
+    let definition = &openapi["definitions"][table];
+    if definition.is_null() {
+        return Some(format!("Tabellen {} finnes ikke", table));
+    }
+    match &definition["properties"][column] {
+        Value::Null => Some("Kolonnen finnes ikke".to_string()),
+        property => check(property),
+    }
+}
+
+fn parameters_problem(
+    openapi: &Value,
+    function: &str,
+    parameters: &[(&str, Kind)],
+) -> Option<String> {
+    let call = &openapi["paths"][format!("/rpc/{}", function)]["post"];
+    if call.is_null() {
+        return Some("Funksjonen finnes ikke".to_string());
+    }
+    let schema = call["parameters"]
+        .as_array()
+        .and_then(|ps| ps.iter().find(|p| p["in"] == "body"))
+        .map(|p| &p["schema"])
+        .unwrap_or(&Value::Null);
+
+    let mut problems = vec![];
+    for (name, kind) in parameters {
+        match &schema["properties"][*name] {
+            Value::Null => problems.push(format!("Parameteren {} finnes ikke", name)),
+            property if !kind.accepts(property) => problems.push(format!(
+                "Parameteren {} er {}, floq forventer {}",
+                name,
+                type_of(property),
+                kind
+            )),
+            _ => {}
+        }
+    }
+    let required = schema["required"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    for name in required.iter().filter_map(Value::as_str) {
+        if !parameters.iter().any(|(p, _)| *p == name) {
+            problems.push(format!("Krever den nye parameteren {}", name));
+        }
+    }
+
+    (!problems.is_empty()).then(|| problems.join(". "))
+}
+
+/// The type of a property as described by PostgREST, e.g. `string (date)`.
+fn type_of(property: &Value) -> String {
+    match (property["type"].as_str(), property["format"].as_str()) {
+        (Some(t), Some(f)) if t != f => format!("{} ({})", t, f),
+        (Some(t), _) => t.to_string(),
+        _ => "ukjent".to_string(),
+    }
+}
+
+/// Every dependency checked against the OpenAPI description.
+fn check_all(openapi: &Value) -> Vec<Check> {
+    DEPENDENCIES.iter().map(|d| d.check(openapi)).collect()
+}
+
+pub async fn execute<T: Write + Send>(
+    out: &mut T,
+    client: &HttpClient,
+    preferences: &Preferences,
+) -> Result<()> {
+    let openapi = client.get_openapi().await?;
+    let checks = check_all(&openapi);
+
+    let mut table_maker = TableMaker::new();
+    table_maker.style(preferences.table_style);
+    table_maker.static_titles(vec!["AVHENGIGHET", "STATUS"]);
+    table_maker
+        .with(Box::new(|c: &Check| c.dependency.clone()))
+        .with(Box::new(|c| {
+            c.problem.clone().unwrap_or_else(|| "OK".to_string())
+        }));
+    table_maker.into_table(&checks).print(out)?;
+
+    let failed = checks.iter().filter(|c| c.problem.is_some()).count();
+    if failed > 0 {
+        return Err(anyhow!(FloqError::ApiChanged).context(format!(
+            "{} av {} avhengigheter er endret i Floq API-et",
+            failed,
+            checks.len()
+        )));
+    }
+
+    Ok(())
+}
+
+impl HttpClient {
+    /// The OpenAPI description of the API, served by PostgREST at the root.
+    async fn get_openapi(&self) -> Result<Value> {
+        let mut response: Response = self
+            .send(|| self.get("").header("Accept", "application/openapi+json"))
+            .await
+            .with_context(|| "Noe gikk galt under henting av OpenAPI-beskrivelsen")?;
+
+        let openapi: Value = response
+            .body_json()
+            .await
+            .handle_malformed_body()
+            .with_context(|| "Klarte ikke å lese OpenAPI-beskrivelsen fra Floq")?;
+        if !openapi["paths"].is_object() || !openapi["definitions"].is_object() {
+            return Err(anyhow!(FloqError::MalformedBody)
+                .context("Floq publiserer ingen OpenAPI-beskrivelse av tabellene og funksjonene"));
+        }
+
+        Ok(openapi)
+    }
+}
+
+#[cfg(test)]
+demonstrate::demonstrate! {
+    describe "check_all" {
+        use super::*;
+
+        before {
+            let openapi: Value =
+                serde_json::from_str(include_str!("../../fixtures/floq-mock-openapi.json")).unwrap();
+        }
+
+        it "accepts the API served by floq-mock" {
+            let problems: Vec<_> = check_all(&openapi)
+                .into_iter()
+                .filter_map(|c| c.problem.map(|p| format!("{}: {}", c.dependency, p)))
+                .collect();
+
+            assert_eq!(problems, Vec::<String>::new());
+        }
+
+        it "reports changed columns and parameters" {
+            let mut openapi = openapi;
+            openapi["definitions"]["time_entry"]["properties"]["minutes"] =
+                serde_json::json!({ "type": "string", "format": "interval" });
+            openapi["definitions"]["projects"]["properties"]
+                .as_object_mut()
+                .unwrap()
+                .remove("active");
+            openapi["paths"]["/rpc/projects_for_employee_for_date"]["post"]["parameters"][0]
+                ["schema"]["required"] = serde_json::json!(["employee_id", "date", "company_id"]);
+
+            let problems: Vec<_> = check_all(&openapi)
+                .into_iter()
+                .filter_map(|c| c.problem.map(|p| format!("{}: {}", c.dependency, p)))
+                .collect();
+
+            assert_eq!(
+                problems,
+                vec![
+                    "projects.active: Kolonnen finnes ikke",
+                    "time_entry.minutes: Er string (interval), floq forventer et heltall",
+                    "rpc/projects_for_employee_for_date(employee_id, date): Krever den nye \
+                     parameteren company_id",
+                ]
+            );
+        }
+    }
+}
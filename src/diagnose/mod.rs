@@ -0,0 +1,50 @@
+use crate::{
+    cmd::{self, Subcommand},
+    http_client::HttpClient,
+    user,
+};
+
+use std::io::Write;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use clap::{App, AppSettings, ArgMatches};
+
+mod api;
+
+const SUBCOMMAND_NAME: &str = "diagnose";
+
+pub fn subcommand_app<'help>() -> App<'help> {
+    App::new(SUBCOMMAND_NAME)
+        .about("Finn ut om floq fortsatt passer med Floq")
+        .setting(AppSettings::ArgRequiredElseHelp)
+        .subcommand(App::new("api").about(
+            "Sjekk at endepunktene, funksjonene og kolonnene floq bruker fortsatt finnes i Floq API-et, med typene floq forventer",
+        ))
+}
+
+pub fn subcommand<T: Write + Send>() -> Box<dyn Subcommand<T>> {
+    Box::new(DiagnoseSubcommand)
+}
+
+struct DiagnoseSubcommand;
+
+#[async_trait(?Send)]
+impl<T: Write + Send> Subcommand<T> for DiagnoseSubcommand {
+    fn matches(&self, matches: &ArgMatches) -> bool {
+        matches.subcommand_name() == Some(SUBCOMMAND_NAME)
+    }
+
+    async fn execute(&self, matches: &ArgMatches, out: &mut T) -> Result<()> {
+        match matches.subcommand() {
+            Some(("api", api_matches)) => {
+                let (user, preferences) = user::load_user_and_preferences(api_matches, out).await?;
+                let client = HttpClient::from_user(&user)?
+                    .with_retry(cmd::retry_policy(api_matches, &preferences));
+
+                api::execute(out, &client, &preferences).await
+            }
+            _ => unreachable!("Unknown commands should be handled by the library"),
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use std::fmt::Display;
+
+use serde::Deserialize;
+use surf::StatusCode;
+
+/// The kinds of errors a script might want to act on. They are added to the error chain as
+/// context, and [`FloqError::exit_code`] decides the exit code of the `floq` command.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FloqError {
+    /// The user must log in (again) before the command can be run.
+    NotAuthenticated { profile: String },
+    /// Floq rejected the access token, and it could not be refreshed.
+    TokenExpired,
+    /// Floq understood who the user is, but the user is not allowed to do this.
+    Forbidden,
+    /// What was asked for does not exist in Floq.
+    NotFound,
+    /// The input was rejected, either by the command itself or by Floq.
+    Validation(String),
+    /// Floq could not be reached.
+    Network,
+    /// Floq failed to handle a valid request.
+    Server(StatusCode),
+    /// The response from Floq was not what the client expected.
+    MalformedBody,
+    /// The stored login is encrypted, and the passphrase is missing or wrong.
+    Passphrase,
+    /// Parts of the Floq API that floq depends on have changed, as found by `floq diagnose api`.
+    ApiChanged,
+}
+
+impl FloqError {
+    /// The exit code of the `floq` command when this is the outermost `FloqError` in the chain.
+    /// Other errors exit with `1`.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            FloqError::NotAuthenticated { .. } => 3,
+            FloqError::TokenExpired => 4,
+            FloqError::Forbidden => 5,
+            FloqError::NotFound => 6,
+            FloqError::Validation(_) => 7,
+            FloqError::Network => 8,
+            FloqError::Server(_) => 9,
+            FloqError::MalformedBody => 10,
+            FloqError::Passphrase => 11,
+            FloqError::ApiChanged => 12,
+        }
+    }
+
+    /// Classifies an error response from Floq by its status code.
+    pub fn from_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::Unauthorized => FloqError::TokenExpired,
+            StatusCode::Forbidden => FloqError::Forbidden,
+            StatusCode::NotFound => FloqError::NotFound,
+            s if s.is_server_error() => FloqError::Server(s),
+            s => FloqError::Validation(format!(
+                "Fikk en feilresponse fra Floq med statuskode {}",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for FloqError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FloqError::NotAuthenticated { profile } => write!(
+                f,
+                "Du er ikke logget inn på profilen {}, kjør \"floq bruker logg-inn\" for å logge inn",
+                profile
+            ),
+            FloqError::TokenExpired => write!(
+                f,
+                "Ikke adgang til Floq API-et, venligst logg inn på nytt. Statuskode {}",
+                StatusCode::Unauthorized
+            ),
+            FloqError::Forbidden => write!(
+                f,
+                "Du har ikke tilgang til dette i Floq. Statuskode {}",
+                StatusCode::Forbidden
+            ),
+            FloqError::NotFound => write!(
+                f,
+                "Fant ikke det som ble etterspurt i Floq. Statuskode {}",
+                StatusCode::NotFound
+            ),
+            FloqError::Validation(message) => write!(f, "{}", message),
+            FloqError::Network => write!(f, "Fikk ikke kontakt med Floq"),
+            FloqError::Server(status) => {
+                write!(f, "Floq klarte ikke å svare, statuskode {}", status)
+            }
+            FloqError::MalformedBody => write!(f, "Klarte ikke å lese svaret fra Floq"),
+            FloqError::Passphrase => write!(
+                f,
+                "Innloggingen er kryptert, og passordet mangler eller er feil. Passordet kan settes med {}",
+                crate::user::PASSPHRASE_ENV
+            ),
+            FloqError::ApiChanged => write!(f, "Floq API-et er endret, floq må kanskje oppdateres"),
+        }
+    }
+}
+
+impl std::error::Error for FloqError {}
+
+/// The body of an error response from PostgREST, which serves the Floq API. The `code` is either
+/// a PostgreSQL error code or one of PostgREST's own, starting with `PGRST`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PostgrestError {
+    pub code: Option<String>,
+    pub message: String,
+    pub details: Option<String>,
+    pub hint: Option<String>,
+}
+
+impl PostgrestError {
+    /// A friendlier explanation of the error, for the codes Floq is known to respond with.
+    pub fn explanation(&self) -> Option<&'static str> {
+        let explanation = match self.code.as_deref()? {
+            "23503" => "Det finnes ikke i Floq, f.eks. et ukjent prosjekt",
+            "23505" => "Det finnes allerede i Floq",
+            "23502" => "En påkrevd verdi mangler",
+            "23514" => "Verdien er ikke tillatt av Floq",
+            "22007" | "22008" => "Ugyldig dato",
+            "22P02" => "Ugyldig verdi",
+            "42501" => "Du har ikke tilgang til dette i Floq",
+            "42703" | "42P01" | "42883" | "PGRST202" => {
+                "Floq kjenner ikke til forespørselen, kanskje floq må oppdateres"
+            }
+            "PGRST100" => "Floq forstod ikke forespørselen",
+            "PGRST301" => "Innloggingen er ugyldig",
+            _ => return None,
+        };
+
+        Some(explanation)
+    }
+}
+
+impl Display for PostgrestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.explanation() {
+            Some(explanation) => write!(f, "{}: {}", explanation, self.message)?,
+            None => write!(f, "{}", self.message)?,
+        }
+        if let Some(code) = &self.code {
+            write!(f, " ({})", code)?;
+        }
+        if let Some(details) = &self.details {
+            write!(f, ". Detaljer: {}", details)?;
+        }
+        if let Some(hint) = &self.hint {
+            write!(f, ". Hint: {}", hint)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for PostgrestError {}
+
+#[cfg(test)]
+demonstrate::demonstrate! {
+    describe "postgrest_error" {
+        use super::*;
+
+        it "explains known codes" {
+            let error: PostgrestError = serde_json::from_str(
+                r#"{
+                    "code": "23503",
+                    "message": "insert or update on table \"time_entry\" violates foreign key constraint",
+                    "details": "Key (project)=(UKJENT) is not present in table \"projects\".",
+                    "hint": null
+                }"#,
+            )
+            .unwrap();
+
+            assert_eq!(
+                error.to_string(),
+                "Det finnes ikke i Floq, f.eks. et ukjent prosjekt: insert or update on table \
+                 \"time_entry\" violates foreign key constraint (23503). Detaljer: Key \
+                 (project)=(UKJENT) is not present in table \"projects\"."
+            );
+        }
+
+        it "keeps the message of unknown codes" {
+            let error: PostgrestError = serde_json::from_str(
+                r#"{"code": "P0001", "message": "Perioden er låst", "hint": "Kontakt regnskap"}"#,
+            )
+            .unwrap();
+
+            assert_eq!(error.to_string(), "Perioden er låst (P0001). Hint: Kontakt regnskap");
+        }
+    }
+}
@@ -1,54 +1,528 @@
-use crate::user::User;
+use crate::{
+    error::{FloqError, PostgrestError},
+    trace::{self, Level},
+    user::{self, config, profile::Profile, CredentialSource, User},
+};
 
-use std::option_env;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+    time::Duration,
+};
 
-use anyhow::{anyhow, Context};
-use surf::StatusCode;
+use anyhow::{anyhow, Context, Result};
+use async_channel::{Receiver, Sender};
+use async_std::{sync::Mutex, task};
+use async_trait::async_trait;
+use http_client::isahc::IsahcClient;
+use isahc::config::{CaCertificate, Configurable};
+use rand::Rng;
+use surf::{http::Method, RequestBuilder, Response, StatusCode, Url};
 
-pub const FLOQ_DOMAIN: Option<&str> = option_env!("FLOQ_DOMAIN");
-pub const FLOQ_API_DOMAIN: Option<&str> = option_env!("FLOQ_API_DOMAIN");
+/// How long a request may take, from connecting until the whole response is read.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many requests are sent at once, e.g. when fetching a long period day by day.
+const MAX_CONCURRENT_REQUESTS: usize = 8;
 
-pub fn floq_domain() -> &'static str {
-    FLOQ_DOMAIN.unwrap_or("https://blank-test.floq.no")
+/// The longest wait between two attempts, however many retries there are.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// Environment variables with the proxy for HTTPS and plain HTTP, and the hosts reached without
+/// it. The first one that is set is used.
+const HTTPS_PROXY_ENV: [&str; 2] = ["HTTPS_PROXY", "https_proxy"];
+const HTTP_PROXY_ENV: [&str; 2] = ["HTTP_PROXY", "http_proxy"];
+const NO_PROXY_ENV: [&str; 2] = ["NO_PROXY", "no_proxy"];
+
+/// The system's CA certificates followed by the ones from the preferences, in the cache dir.
+const CA_BUNDLE_FILE_NAME: &str = "ca-sertifikater.pem";
+
+/// How requests that fail because of the network or Floq are retried.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// How many times a failed request is sent again, `0` to never retry.
+    pub retries: u32,
+    /// The wait before the first retry, doubled for each of the next with some random jitter.
+    pub delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Never retry.
+    pub fn none() -> Self {
+        RetryPolicy {
+            retries: 0,
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// The wait before `retry`, counting from 1. Between half and all of the doubled delay, so
+    /// that concurrent requests don't all retry at once.
+    fn delay(&self, retry: u32) -> Duration {
+        let delay = self
+            .delay
+            .saturating_mul(2u32.saturating_pow(retry - 1))
+            .min(MAX_RETRY_DELAY);
+        delay.mul_f64(rand::thread_rng().gen_range(0.5, 1.0))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        let preferences = user::Preferences::default();
+        RetryPolicy {
+            retries: preferences.retries,
+            delay: Duration::from_millis(preferences.retry_delay_ms),
+        }
+    }
+}
+
+/// A client sending every request to `base_url`, reusing connections and with timeouts. The
+/// requests are logged as set by [`trace::set_level`].
+pub fn surf_client(base_url: &str, profile: &Profile) -> Result<surf::Client> {
+    surf_client_with_env(base_url, profile, &|name| env::var(name).ok())
+}
+
+/// [`surf_client`] with the proxy variables looked up by `env` instead of in the environment.
+fn surf_client_with_env(
+    base_url: &str,
+    profile: &Profile,
+    env: &dyn Fn(&str) -> Option<String>,
+) -> Result<surf::Client> {
+    // without the trailing slash, the last part of the path would be replaced by the request path
+    let base_url = format!("{}/", base_url.trim_end_matches('/'));
+    let base_url =
+        Url::parse(&base_url).with_context(|| format!("Ugyldig adresse {}", base_url))?;
+
+    let mut builder = isahc::HttpClient::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .connect_timeout(CONNECT_TIMEOUT)
+        .proxy(proxy_for(&base_url, env)?);
+    if let Some(extra) = &profile.ca_certificates {
+        builder = builder.ssl_ca_certificate(CaCertificate::file(ca_bundle(extra)?));
+    }
+    let http_client = builder
+        .build()
+        .with_context(|| "Klarte ikke å sette opp HTTP-klienten")?;
+
+    let mut client = surf::Client::with_http_client(IsahcClient::from_client(http_client))
+        .with(trace::HttpLogger);
+    client.set_base_url(base_url);
+    Ok(client)
+}
+
+/// The proxy for requests to `url`, from `HTTPS_PROXY` or `HTTP_PROXY` depending on the scheme,
+/// unless the host is listed in `NO_PROXY`. It's always set, so that curl doesn't pick a proxy
+/// from other variables on its own.
+fn proxy_for(url: &Url, env: &dyn Fn(&str) -> Option<String>) -> Result<Option<isahc::http::Uri>> {
+    let names = match url.scheme() {
+        "https" => HTTPS_PROXY_ENV,
+        _ => HTTP_PROXY_ENV,
+    };
+    let (name, proxy) = match first_env_var(&names, env) {
+        Some(var) => var,
+        None => return Ok(None),
+    };
+    let host = url.host_str().unwrap_or_default();
+    if let Some((_, no_proxy)) = first_env_var(&NO_PROXY_ENV, env) {
+        if bypasses_proxy(host, &no_proxy) {
+            return Ok(None);
+        }
+    }
+
+    // the value isn't part of the error, as it may contain a password
+    let proxy: isahc::http::Uri = proxy
+        .parse()
+        .with_context(|| FloqError::Validation(format!("Ugyldig proxy-adresse i {}", name)))?;
+    if trace::enabled(Level::Requests) {
+        eprintln!(
+            "Bruker proxyen {} fra {} for {}",
+            proxy.authority().map(|a| a.as_str()).unwrap_or_default(),
+            name,
+            host
+        );
+    }
+    Ok(Some(proxy))
 }
 
-pub fn floq_api_domain() -> &'static str {
-    FLOQ_API_DOMAIN.unwrap_or("https://api-blank-test.floq.no")
+fn first_env_var(
+    names: &[&'static str],
+    env: &dyn Fn(&str) -> Option<String>,
+) -> Option<(&'static str, String)> {
+    names.iter().find_map(|&name| {
+        env(name)
+            .filter(|v| !v.trim().is_empty())
+            .map(|v| (name, v.trim().to_string()))
+    })
 }
 
+/// Whether `host` is listed in `no_proxy`, a comma separated list of host names where `*` matches
+/// every host and `example.com` also matches its subdomains.
+fn bypasses_proxy(host: &str, no_proxy: &str) -> bool {
+    let host = host.to_lowercase();
+    no_proxy
+        .split(',')
+        .map(|entry| entry.trim().to_lowercase())
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| {
+            let domain = entry.trim_start_matches("*.").trim_start_matches('.');
+            entry == "*" || host == domain || host.ends_with(&format!(".{}", domain))
+        })
+}
+
+/// A CA bundle with the system's certificates and the ones in `extra`. Curl reads the trusted
+/// certificates from a single file, so the two are combined in a file in the cache dir.
+fn ca_bundle(extra: &Path) -> Result<PathBuf> {
+    let extra_certificates = fs::read(extra).with_context(|| {
+        FloqError::Validation(format!(
+            "Klarte ikke å lese CA-sertifikatene i {}, se \"floq bruker profiler ca-sertifikater\"",
+            extra.display()
+        ))
+    })?;
+    if !is_pem(&extra_certificates) {
+        return Err(anyhow!(FloqError::Validation(format!(
+            "Fant ingen sertifikater i PEM-format i {}",
+            extra.display()
+        ))));
+    }
+
+    let mut bundle = system_certificates()?;
+    if !bundle.ends_with(b"\n") {
+        bundle.push(b'\n');
+    }
+    bundle.extend(extra_certificates);
+
+    let dir = config::cache_dir()?;
+    let path = dir.join(CA_BUNDLE_FILE_NAME);
+    if fs::read(&path).ok().as_deref() != Some(bundle.as_slice()) {
+        // written next to it and moved in place, so that another floq command never reads half
+        let partial = dir.join(format!("{}.{}", CA_BUNDLE_FILE_NAME, process::id()));
+        fs::create_dir_all(&dir)
+            .and_then(|_| fs::write(&partial, &bundle))
+            .and_then(|_| fs::rename(&partial, &path))
+            .with_context(|| {
+                format!(
+                    "Klarte ikke å skrive CA-sertifikatene til {}",
+                    path.display()
+                )
+            })?;
+    }
+
+    Ok(path)
+}
+
+/// The certificates curl trusts when it's not given any, from the bundle found by `openssl_probe`
+/// or the one curl was built with, or else every certificate in the system's directory of them.
+/// The bundle replaces curl's own, so floq refuses to go on without them rather than trusting
+/// nothing but the extra certificates.
+fn system_certificates() -> Result<Vec<u8>> {
+    let probe = openssl_probe::probe();
+    let curl = curl::Version::get();
+
+    let files = probe
+        .cert_file
+        .into_iter()
+        .chain(curl.cainfo().map(PathBuf::from));
+    for file in files {
+        if let Ok(certificates) = fs::read(&file) {
+            if is_pem(&certificates) {
+                return Ok(certificates);
+            }
+        }
+    }
+
+    let dirs = probe
+        .cert_dir
+        .into_iter()
+        .chain(curl.capath().map(PathBuf::from));
+    for dir in dirs {
+        let mut files: Vec<PathBuf> = match fs::read_dir(&dir) {
+            Ok(entries) => entries.filter_map(|e| e.ok().map(|e| e.path())).collect(),
+            Err(_) => continue,
+        };
+        files.sort();
+
+        // the same certificate is often linked to by its hash as well
+        let mut certificates: Vec<Vec<u8>> = vec![];
+        for file in files {
+            if let Ok(mut certificate) = fs::read(&file) {
+                if is_pem(&certificate) && !certificates.contains(&certificate) {
+                    if !certificate.ends_with(b"\n") {
+                        certificate.push(b'\n');
+                    }
+                    certificates.push(certificate);
+                }
+            }
+        }
+        if !certificates.is_empty() {
+            return Ok(certificates.concat());
+        }
+    }
+
+    Err(anyhow!(FloqError::Validation(
+        "Fant ikke systemets CA-sertifikater, som de ekstra CA-sertifikatene legges til. Sett SSL_CERT_FILE til en PEM-fil med dem".to_string()
+    )))
+}
+
+fn is_pem(certificates: &[u8]) -> bool {
+    String::from_utf8_lossy(certificates).contains("-----BEGIN CERTIFICATE-----")
+}
+
+/// Client for the Floq API, authenticated as a [`User`]. The methods for each part of the API are
+/// found next to the types they return.
 pub struct HttpClient {
-    pub access_token: String,
+    pub api_domain: String,
     pub employee_id: u16,
+    client: surf::Client,
+    limiter: Limiter,
+    profile: Profile,
+    access_token: Mutex<String>,
+    refresh_token: Option<String>,
+    credential_source: CredentialSource,
+    retry: RetryPolicy,
 }
 
 impl HttpClient {
-    pub fn from_user(user: &User) -> Self {
-        Self {
-            access_token: user.access_token.clone(),
+    pub fn from_user(user: &User) -> Result<Self> {
+        Ok(Self {
+            api_domain: user.profile.api_domain.clone(),
             employee_id: user.employee_id,
+            client: surf_client(&user.profile.api_domain, &user.profile)?,
+            limiter: Limiter::new(MAX_CONCURRENT_REQUESTS),
+            profile: user.profile.clone(),
+            access_token: Mutex::new(user.access_token.clone()),
+            refresh_token: user.refresh_token.clone(),
+            credential_source: user.source,
+            retry: RetryPolicy::default(),
+        })
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// A `GET` request to `path`, relative to the API domain.
+    pub fn get(&self, path: &str) -> RequestBuilder {
+        self.client.get(path).header("Accept", "application/json")
+    }
+
+    /// A `POST` request with a JSON body to `path`, relative to the API domain.
+    pub fn post(&self, path: &str) -> RequestBuilder {
+        self.client
+            .post(path)
+            .header("Accept", "application/json")
+            .header("Content-Type", "application/json")
+    }
+
+    /// A request with any method and a JSON body to `path`, relative to the API domain.
+    pub fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let request = match method {
+            Method::Get => self.client.get(path),
+            Method::Head => self.client.head(path),
+            Method::Post => self.client.post(path),
+            Method::Put => self.client.put(path),
+            Method::Delete => self.client.delete(path),
+            Method::Connect => self.client.connect(path),
+            Method::Options => self.client.options(path),
+            Method::Trace => self.client.trace(path),
+            Method::Patch => self.client.patch(path),
+        };
+        request
+            .header("Accept", "application/json")
+            .header("Content-Type", "application/json")
+    }
+
+    /// Sends the request built by `build` with the access token. If Floq rejects the token the
+    /// token is refreshed once, and the request is built and sent again.
+    ///
+    /// Requests that can safely be sent twice, i.e. all but `POST` and `PATCH`, are retried if the
+    /// network or Floq fails. Other requests are only retried if they never reached Floq.
+    pub async fn send<F>(&self, build: F) -> Result<Response>
+    where
+        F: Fn() -> RequestBuilder,
+    {
+        let idempotent = !matches!(build().build().method(), Method::Post | Method::Patch);
+        self.send_with_retry(build, idempotent).await
+    }
+
+    /// Like [`HttpClient::send`], for `POST` requests to RPC functions that only read from Floq,
+    /// and thus can be retried like any other read.
+    pub async fn send_read<F>(&self, build: F) -> Result<Response>
+    where
+        F: Fn() -> RequestBuilder,
+    {
+        self.send_with_retry(build, true).await
+    }
+
+    async fn send_with_retry<F>(&self, build: F, idempotent: bool) -> Result<Response>
+    where
+        F: Fn() -> RequestBuilder,
+    {
+        let mut retry = 0;
+        loop {
+            match self.send_once(&build).await {
+                Err(e) if retry < self.retry.retries && is_transient(&e, idempotent) => {
+                    retry += 1;
+                    let delay = self.retry.delay(retry);
+                    if trace::enabled(trace::Level::Requests) {
+                        let request = build().build();
+                        eprintln!(
+                            "{} {} feilet, prøver igjen om {} ms ({} av {}): {:#}",
+                            request.method(),
+                            request.url().path(),
+                            delay.as_millis(),
+                            retry,
+                            self.retry.retries,
+                            e
+                        );
+                    }
+                    task::sleep(delay).await;
+                }
+                r => return r,
+            }
+        }
+    }
+
+    async fn send_once<F>(&self, build: &F) -> Result<Response>
+    where
+        F: Fn() -> RequestBuilder,
+    {
+        let access_token = self.access_token.lock().await.clone();
+        let response = self.send_authorized(build, &access_token).await;
+
+        match response {
+            Ok(r) if r.status() == StatusCode::Unauthorized && self.refresh_token.is_some() => {
+                let access_token = self.refresh_access_token(&access_token).await?;
+
+                self.send_authorized(build, &access_token)
+                    .await
+                    .handle_floq_response()
+                    .await
+            }
+            r => r.handle_floq_response().await,
+        }
+    }
+
+    async fn send_authorized<F>(&self, build: &F, access_token: &str) -> surf::Result<Response>
+    where
+        F: Fn() -> RequestBuilder,
+    {
+        let _permit = self.limiter.acquire().await;
+        build()
+            .header("Authorization", format!("Bearer {}", access_token))
+            .send()
+            .await
+    }
+
+    /// Refreshes the access token, unless it has already been replaced since `rejected_token`
+    /// was used, e.g. by a concurrent request.
+    async fn refresh_access_token(&self, rejected_token: &str) -> Result<String> {
+        let mut access_token = self.access_token.lock().await;
+
+        if *access_token == rejected_token {
+            let refresh_token = self.refresh_token.as_deref().unwrap_or_default();
+            *access_token = user::refresh_access_token(
+                &self.profile,
+                rejected_token,
+                refresh_token,
+                self.credential_source,
+            )
+            .await
+            .with_context(|| "Floq avviste innloggingen din og den kunne ikke fornyes")
+            .map_err(|e| user::login_required(e, &self.profile))?;
         }
+
+        Ok(access_token.clone())
     }
 }
 
+#[async_trait(?Send)]
 pub trait HandleInvalidToken {
-    fn handle_floq_response(self) -> Result<surf::Response, anyhow::Error>;
+    async fn handle_floq_response(self) -> Result<surf::Response, anyhow::Error>;
+}
+
+/// Limits how many requests are sent at once, by handing out a fixed number of permits.
+struct Limiter {
+    sender: Sender<()>,
+    receiver: Receiver<()>,
+}
+
+struct Permit<'a>(&'a Sender<()>);
+
+impl Limiter {
+    fn new(permits: usize) -> Self {
+        let (sender, receiver) = async_channel::bounded(permits);
+        for _ in 0..permits {
+            let _ = sender.try_send(());
+        }
+        Limiter { sender, receiver }
+    }
+
+    /// Waits until a permit is free. The permit is given back when it's dropped.
+    async fn acquire(&self) -> Permit<'_> {
+        // the limiter keeps a sender, so the channel is never closed
+        let _ = self.receiver.recv().await;
+        Permit(&self.sender)
+    }
 }
 
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        let _ = self.0.try_send(());
+    }
+}
+
+/// Whether sending the request again might succeed. Requests that are not idempotent are only
+/// retried if they were never sent, i.e. the connection could not be made.
+fn is_transient(e: &anyhow::Error, idempotent: bool) -> bool {
+    match e.downcast_ref::<FloqError>() {
+        // an untrusted certificate stays untrusted
+        Some(FloqError::Network)
+            if matches!(
+                e.downcast_ref::<isahc::Error>(),
+                Some(isahc::Error::BadServerCertificate(_))
+            ) =>
+        {
+            false
+        }
+        Some(FloqError::Network) if idempotent => true,
+        Some(FloqError::Network) => matches!(
+            e.downcast_ref::<isahc::Error>(),
+            Some(isahc::Error::ConnectFailed) | Some(isahc::Error::CouldntResolveHost)
+        ),
+        Some(FloqError::Server(_)) => idempotent,
+        _ => false,
+    }
+}
+
+#[async_trait(?Send)]
 impl HandleInvalidToken for surf::Result<surf::Response> {
-    fn handle_floq_response(self) -> Result<surf::Response, anyhow::Error> {
-        self
-            .map_err(|e| e.downcast().unwrap_or_else(|e2| anyhow!(e2)))
-            .and_then(|r| match r.status() {
-                StatusCode::Unauthorized | StatusCode::Forbidden => Err(anyhow!(
-                    "Ikke adgang til Floq API-et, venligst logg inn på nytt. Statuskode {}",
-                    r.status()
-                )),
-                s if s.is_client_error() || s.is_server_error() => Err(anyhow!(
-                    "Fikk en feilresponse fra Floq med statuskode {}",
-                    s
-                )),
-                _ => Ok(r),
-            })
+    /// Turns error responses into a [`FloqError`], caused by the [`PostgrestError`] in the body
+    /// if there is one.
+    async fn handle_floq_response(self) -> Result<surf::Response, anyhow::Error> {
+        // the error from the HTTP client is kept, so that it can be told whether anything was sent
+        let mut response = self.map_err(|e| match e.downcast::<isahc::Error>() {
+            Ok(e @ isahc::Error::BadServerCertificate(_)) => anyhow!(e)
+                .context(
+                    "Sertifikatet til Floq er ikke klarert, se \"floq bruker profiler \
+                     ca-sertifikater\" hvis nettverket ditt inspiserer TLS",
+                )
+                .context(FloqError::Network),
+            Ok(e) => anyhow!(e).context(FloqError::Network),
+            Err(e2) => anyhow!(e2).context(FloqError::Network),
+        })?;
+
+        match response.status() {
+            s if s.is_client_error() || s.is_server_error() => {
+                let error = FloqError::from_status(s);
+                match response.body_json::<PostgrestError>().await {
+                    Ok(body) => Err(anyhow!(body).context(error)),
+                    Err(_) => Err(anyhow!(error)),
+                }
+            }
+            _ => Ok(response),
+        }
     }
 }
 
@@ -58,8 +532,228 @@ pub trait HandleMalformedBody<T> {
 
 impl<T> HandleMalformedBody<T> for surf::Result<T> {
     fn handle_malformed_body(self) -> Result<T, anyhow::Error> {
-        self
-            .map_err(|e| e.downcast().unwrap_or_else(|e2| anyhow!(e2)))
-            .with_context(|| "Klarte ikke å lese svaret fra Floq")
+        self.map_err(|e| {
+            e.downcast()
+                .unwrap_or_else(|e2| anyhow!(e2))
+                .context(FloqError::MalformedBody)
+        })
+    }
+}
+
+#[cfg(test)]
+demonstrate::demonstrate! {
+    describe "bypasses_proxy" {
+        use super::*;
+
+        it "matches hosts and their subdomains" {
+            let no_proxy = "localhost, .intern.example.com,floq.no";
+
+            assert!(bypasses_proxy("localhost", no_proxy));
+            assert!(bypasses_proxy("api.intern.example.com", no_proxy));
+            assert!(bypasses_proxy("api-blank.FLOQ.no", no_proxy));
+            assert!(!bypasses_proxy("example.com", no_proxy));
+            assert!(!bypasses_proxy("notfloq.no", no_proxy));
+        }
+
+        it "matches every host with a wildcard" {
+            assert!(bypasses_proxy("api-blank.floq.no", "*"));
+            assert!(!bypasses_proxy("api-blank.floq.no", ""));
+        }
+    }
+
+    describe "proxy_for" {
+        use super::*;
+        use std::sync::mpsc;
+
+        it "sends requests through the proxy in HTTP_PROXY" {
+            // a proxy answering every request itself, telling what it was asked for
+            let (tx, rx) = mpsc::channel();
+            let (addr_tx, addr_rx) = mpsc::channel();
+            std::thread::spawn(move || {
+                let server = rouille::Server::new("127.0.0.1:0", move |request| {
+                    tx.send(format!("{} {}", request.method(), request.raw_url())).unwrap();
+                    rouille::Response::json(&Vec::<String>::new())
+                })
+                .unwrap();
+                addr_tx.send(server.server_addr()).unwrap();
+                server.run();
+            });
+            let proxy = format!("http://{}", addr_rx.recv().unwrap());
+            let env = |name: &str| match name {
+                "HTTP_PROXY" => Some(proxy.clone()),
+                "NO_PROXY" => Some("127.0.0.1,localhost".to_string()),
+                _ => None,
+            };
+
+            let profile = Profile {
+                name: "test".to_string(),
+                domain: "http://floq.example".to_string(),
+                api_domain: "http://api.floq.example".to_string(),
+                ca_certificates: None,
+            };
+            let client = surf_client_with_env(&profile.api_domain, &profile, &env).unwrap();
+            let response = task::block_on(client.get("projects").send()).unwrap();
+
+            assert_eq!(response.status(), StatusCode::Ok);
+            assert_eq!(rx.recv().unwrap(), "GET http://api.floq.example/projects");
+        }
+    }
+
+    describe "is_transient" {
+        use super::*;
+
+        it "retries server errors only if the request can be sent twice" {
+            let e = anyhow!(FloqError::Server(StatusCode::ServiceUnavailable));
+
+            assert!(is_transient(&e, true));
+            assert!(!is_transient(&e, false));
+        }
+
+        it "retries failed connections of any request" {
+            let connect_failed = anyhow!(isahc::Error::ConnectFailed).context(FloqError::Network);
+            let timeout = anyhow!(isahc::Error::Timeout).context(FloqError::Network);
+
+            assert!(is_transient(&connect_failed, false));
+            assert!(is_transient(&timeout, true));
+            assert!(!is_transient(&timeout, false));
+        }
+
+        it "never retries errors that would happen again" {
+            let untrusted = anyhow!(isahc::Error::BadServerCertificate(None))
+                .context(FloqError::Network);
+
+            assert!(!is_transient(&untrusted, true));
+            for error in [
+                FloqError::TokenExpired,
+                FloqError::Forbidden,
+                FloqError::NotFound,
+                FloqError::Validation("Ugyldig dato".to_string()),
+                FloqError::MalformedBody,
+            ] {
+                assert!(!is_transient(&anyhow!(error), true));
+            }
+        }
+    }
+
+    describe "retry_policy" {
+        use super::*;
+
+        it "waits between half and all of the doubled delay" {
+            let policy = RetryPolicy {
+                retries: 3,
+                delay: Duration::from_millis(100),
+            };
+
+            for (retry, max) in [(1, 100), (2, 200), (3, 400)] {
+                let delay = policy.delay(retry);
+
+                assert!(delay >= Duration::from_millis(max / 2));
+                assert!(delay <= Duration::from_millis(max));
+            }
+        }
+
+        it "caps the delay" {
+            let policy = RetryPolicy {
+                retries: 100,
+                delay: Duration::from_secs(1),
+            };
+
+            for retry in [5, 10, 40, 100] {
+                assert!(policy.delay(retry) <= MAX_RETRY_DELAY);
+            }
+        }
+    }
+
+    describe "send" {
+        use super::*;
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc, Arc,
+        };
+
+        before {
+            // a Floq that is always unavailable, counting the requests it gets
+            let requests = Arc::new(AtomicUsize::new(0));
+            let counted = requests.clone();
+            let (tx, rx) = mpsc::channel();
+            std::thread::spawn(move || {
+                let server = rouille::Server::new("127.0.0.1:0", move |_| {
+                    counted.fetch_add(1, Ordering::SeqCst);
+                    rouille::Response::text("").with_status_code(503)
+                })
+                .unwrap();
+                tx.send(server.server_addr()).unwrap();
+                server.run();
+            });
+            let domain = format!("http://{}", rx.recv().unwrap());
+
+            let client = HttpClient::from_user(&User {
+                employee_id: 1,
+                email: "ola@blank.no".to_string(),
+                name: "Ola Nordmann".to_string(),
+                access_token: "token".to_string(),
+                refresh_token: None,
+                profile: Profile {
+                    name: "test".to_string(),
+                    domain: domain.clone(),
+                    api_domain: domain,
+                    ca_certificates: None,
+                },
+                source: CredentialSource::Environment,
+            })
+            .unwrap()
+            .with_retry(RetryPolicy {
+                retries: 2,
+                delay: Duration::from_millis(1),
+            });
+        }
+
+        it "retries requests that can be sent twice" {
+            let error = task::block_on(client.send(|| client.get("projects"))).unwrap_err();
+
+            assert_eq!(
+                error.downcast_ref(),
+                Some(&FloqError::Server(StatusCode::ServiceUnavailable))
+            );
+            assert_eq!(requests.load(Ordering::SeqCst), 3);
+        }
+
+        it "sends other requests only once" {
+            for method in [Method::Post, Method::Patch] {
+                requests.store(0, Ordering::SeqCst);
+
+                let error = task::block_on(client.send(|| client.request(method, "time_entry")))
+                    .unwrap_err();
+
+                assert_eq!(
+                    error.downcast_ref(),
+                    Some(&FloqError::Server(StatusCode::ServiceUnavailable))
+                );
+                assert_eq!(requests.load(Ordering::SeqCst), 1);
+            }
+        }
+    }
+
+    describe "limiter" {
+        use super::*;
+        use futures::future;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        it "hands out no more than the given number of permits at once" {
+            let limiter = Limiter::new(MAX_CONCURRENT_REQUESTS);
+            let in_flight = AtomicUsize::new(0);
+            let most_in_flight = AtomicUsize::new(0);
+
+            let requests = (0..MAX_CONCURRENT_REQUESTS * 3).map(|_| async {
+                let _permit = limiter.acquire().await;
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                most_in_flight.fetch_max(now, Ordering::SeqCst);
+                task::sleep(Duration::from_millis(10)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+            });
+            task::block_on(future::join_all(requests));
+
+            assert_eq!(most_in_flight.load(Ordering::SeqCst), MAX_CONCURRENT_REQUESTS);
+        }
     }
 }
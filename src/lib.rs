@@ -0,0 +1,39 @@
+//! Floq i din lokale terminal.
+//!
+//! The `floq` binary is a thin wrapper around [`cli`], and everything it does is available to other
+//! programs through this library: log in with [`load_user`], then use the methods of [`FloqApi`]
+//! on a [`HttpClient`] to read and stamp hours.
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use floq::FloqApi;
+//!
+//! let user = floq::load_user(None, false, &mut std::io::sink()).await?;
+//! let client = floq::HttpClient::from_user(&user)?;
+//!
+//! for project in client.get_projects().await? {
+//!     println!("{}: {}", project.id, project.name);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod api;
+pub mod cli;
+pub mod cmd;
+pub mod diagnose;
+pub mod error;
+pub mod http_client;
+pub mod print;
+pub mod project;
+pub mod time;
+pub mod timestamp;
+pub mod trace;
+pub mod user;
+
+pub use api::FloqApi;
+pub use error::{FloqError, PostgrestError};
+pub use http_client::HttpClient;
+pub use project::{Customer, Project};
+pub use timestamp::history::{ProjectTimestamp, ProjectTimestamps, Timestamp};
+pub use user::{load_user, profile::Profile, Employee, Preferences, User, WeekendDisplay};
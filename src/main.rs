@@ -1,43 +1,37 @@
-use cmd::Subcommand;
+use floq::{cli, trace, FloqError};
 
-use std::io;
+use std::{io, process};
 
 use anyhow::Result;
 use async_std::task;
-use clap::{App, AppSettings};
-
-mod cmd;
-mod http_client;
-mod print;
-mod project;
-mod time;
-mod timestamp;
-mod user;
-
-const VERSION: &str = env!("CARGO_PKG_VERSION");
+use clap::ArgMatches;
 
 fn main() -> Result<()> {
-    let matches = App::new("floq")
-        .about("Floq i din lokale terminal")
-        .version(VERSION)
-        .author("Rust-gjengen")
-        .setting(AppSettings::ArgRequiredElseHelp)
-        .subcommand(user::subcommand_app().display_order(1))
-        .subcommand(project::subcommand_app().display_order(2))
-        .subcommand(timestamp::subcommand_app().display_order(3))
-        .subcommand(timestamp::history::subcommand_app().display_order(4))
-        .get_matches();
-
-    let commands: [Box<dyn Subcommand<_>>; 4] = [
-        user::subcommand(),
-        project::subcommand(),
-        timestamp::subcommand(),
-        timestamp::history::subcommand(),
-    ];
+    let matches = cli::app().get_matches();
+
+    let result = cli::log_level(&matches).and_then(|level| {
+        trace::set_level(level);
+        run(&matches)
+    });
+
+    match result {
+        Err(e) => match e.downcast_ref::<FloqError>() {
+            Some(floq_error) => {
+                eprintln!("Error: {:?}", e);
+                process::exit(floq_error.exit_code())
+            }
+            None => Err(e),
+        },
+        r => r,
+    }
+}
 
+fn run(matches: &ArgMatches) -> Result<()> {
     match matches.subcommand() {
         Some((_, sub_matches)) => {
-            let command = commands.into_iter().find(|sc| sc.matches(&matches));
+            let command = cli::subcommands()
+                .into_iter()
+                .find(|sc| sc.matches(matches));
 
             match command {
                 Some(sc) => {
@@ -1,8 +1,42 @@
 use prettytable::{format, Cell, Row, Table};
+use serde::{Deserialize, Serialize};
+
+type Extractor<T> = Box<dyn Fn(&T) -> String>;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum TableStyle {
+    /// No borders and wide columns.
+    #[default]
+    #[serde(rename = "enkel")]
+    Simple,
+    /// No borders and narrow columns.
+    #[serde(rename = "kompakt")]
+    Compact,
+    /// Borders around every cell.
+    #[serde(rename = "rammer")]
+    Boxed,
+}
+
+impl TableStyle {
+    fn format(self) -> format::TableFormat {
+        match self {
+            TableStyle::Simple => format::FormatBuilder::new().padding(0, 6).build(),
+            TableStyle::Compact => format::FormatBuilder::new().padding(0, 2).build(),
+            TableStyle::Boxed => *format::consts::FORMAT_BOX_CHARS,
+        }
+    }
+}
 
 pub struct TableMaker<T> {
     titles: Vec<String>,
-    extractors: Vec<Box<dyn Fn(&T) -> String>>,
+    extractors: Vec<Extractor<T>>,
+    style: TableStyle,
+}
+
+impl<T> Default for TableMaker<T> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<T> TableMaker<T> {
@@ -10,9 +44,15 @@ impl<T> TableMaker<T> {
         TableMaker {
             titles: vec![],
             extractors: vec![],
+            style: TableStyle::default(),
         }
     }
 
+    pub fn style(&mut self, style: TableStyle) -> &mut Self {
+        self.style = style;
+        self
+    }
+
     pub fn titles(&mut self, titles: Vec<String>) {
         self.titles = titles;
     }
@@ -21,7 +61,7 @@ impl<T> TableMaker<T> {
         self.titles = titles.into_iter().map(|s| s.to_string()).collect();
     }
 
-    pub fn with(&mut self, extractor: Box<dyn Fn(&T) -> String>) -> &mut Self {
+    pub fn with(&mut self, extractor: Extractor<T>) -> &mut Self {
         self.extractors.push(extractor);
         self
     }
@@ -29,10 +69,12 @@ impl<T> TableMaker<T> {
     pub fn into_table(self, rows: &[T]) -> Table {
         let mut table = Table::new();
 
-        let format = format::FormatBuilder::new().padding(0, 6).build();
-        table.set_format(format);
-
-        let TableMaker { titles, extractors } = self;
+        let TableMaker {
+            titles,
+            extractors,
+            style,
+        } = self;
+        table.set_format(style.format());
 
         let titles = titles
             .into_iter()
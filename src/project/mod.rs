@@ -1,6 +1,6 @@
-use crate::cmd::Subcommand;
-use crate::http_client::floq_api_domain;
-use crate::http_client::{HandleInvalidToken, HandleMalformedBody, HttpClient};
+use crate::api::FloqApi;
+use crate::cmd::{self, Subcommand};
+use crate::http_client::{HandleMalformedBody, HttpClient};
 use crate::print::TableMaker;
 use crate::user;
 
@@ -25,7 +25,7 @@ pub fn subcommand_app<'help>() -> App<'help> {
                 .short('m')
                 .default_value("true")
                 .conflicts_with("alle")
-                .about("Vis prosjekter du har ført timer på de siste to ukene"),
+                .about("Vis prosjekter du har ført timer på de siste ukene, to uker hvis ikke annet er satt i preferansene"),
         )
         .arg(
             Arg::new("alle")
@@ -49,57 +49,63 @@ impl<T: Write + Send> Subcommand<T> for ProjectsSubcommand {
     }
 
     async fn execute(&self, matches: &clap::ArgMatches, out: &mut T) -> Result<()> {
-        let user = user::load_user_from_config(out).await?;
-        let client = HttpClient::from_user(&user);
-
-        let all = matches.is_present("alle");
-        let mut projects = if all {
-            client.get_projects().await?
-        } else {
-            client
-                .get_current_timestamped_projects_for_employee()
-                .await?
-        };
-        projects.sort_by(|p1, p2| p1.id.cmp(&p2.id));
-
-        let mut table_maker = TableMaker::new();
-        table_maker.static_titles(vec!["ID", "KUNDE", "BESKRIVELSE"]);
-        table_maker
-            .with(Box::new(|p: &Project| p.id.clone()))
-            .with(Box::new(|p| p.customer.name.clone()))
-            .with(Box::new(|p| p.name.clone()));
-        table_maker.into_table(&projects).print(out)?;
-
-        Ok(())
+        let (user, preferences) = user::load_user_and_preferences(matches, out).await?;
+        let client =
+            HttpClient::from_user(&user)?.with_retry(cmd::retry_policy(matches, &preferences));
+
+        execute(matches, out, &client, &preferences).await
     }
 }
 
-#[derive(Deserialize, Debug)]
+async fn execute<T: Write + Send>(
+    matches: &ArgMatches,
+    out: &mut T,
+    api: &dyn FloqApi,
+    preferences: &user::Preferences,
+) -> Result<()> {
+    let all = matches.is_present("alle");
+    let mut projects = if all {
+        api.get_projects().await?
+    } else {
+        let today = Utc::now().date().naive_local();
+        api.get_timestamped_projects_for_employee(today, preferences.history_weeks)
+            .await?
+    };
+    projects.sort_by(|p1, p2| p1.id.cmp(&p2.id));
+
+    let mut table_maker = TableMaker::new();
+    table_maker.style(preferences.table_style);
+    table_maker.static_titles(vec!["ID", "KUNDE", "BESKRIVELSE"]);
+    table_maker
+        .with(Box::new(|p: &Project| p.id.clone()))
+        .with(Box::new(|p| p.customer.name.clone()))
+        .with(Box::new(|p| p.name.clone()));
+    table_maker.into_table(&projects).print(out)?;
+
+    Ok(())
+}
+
+/// A project hours can be stamped on.
+#[derive(Deserialize, Debug, Clone)]
 pub struct Project {
     pub id: String,
     pub name: String,
-    active: bool,
+    pub active: bool,
     pub customer: Customer,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct Customer {
     pub id: String,
     pub name: String,
 }
 
 impl HttpClient {
+    /// All projects in Floq, including the inactive ones.
     pub async fn get_projects(&self) -> Result<Vec<Project>> {
-        let url = format!(
-            "{}/projects?select=id,name,active,customer{{id,name}}",
-            floq_api_domain()
-        );
-        let mut response: Response = surf::get(url)
-            .header("Accept", "application/json")
-            .header("Authorization", format!("Bearer {}", self.access_token))
-            .send()
+        let mut response: Response = self
+            .send(|| self.get("projects?select=id,name,active,customer{id,name}"))
             .await
-            .handle_floq_response()
             .with_context(|| "Noe gikk galt under henting av alle prosjekter")?;
 
         let projects: Vec<Project> = response.body_json().await.handle_malformed_body()?;
@@ -138,18 +144,24 @@ impl ProjectForEmployeeResponse {
 }
 
 impl HttpClient {
-    pub async fn get_current_timestamped_projects_for_employee(&self) -> Result<Vec<Project>> {
+    /// Projects stamped on in the given number of weeks before today, and the rest of this week.
+    pub async fn get_current_timestamped_projects_for_employee(
+        &self,
+        weeks: u32,
+    ) -> Result<Vec<Project>> {
         let today = Utc::now().date();
 
-        self.get_timestamped_projects_for_employee(today.naive_local())
+        self.get_timestamped_projects_for_employee(today.naive_local(), weeks)
             .await
     }
 
+    /// Projects stamped on in the given number of weeks before `date`, and the rest of its week.
     pub async fn get_timestamped_projects_for_employee(
         &self,
         date: NaiveDate,
+        weeks: u32,
     ) -> Result<Vec<Project>> {
-        let lower = date - Duration::weeks(2);
+        let lower = date - Duration::weeks(weeks as i64);
         let upper = date + Duration::days(1) * (6 - date.weekday().num_days_from_monday() as i32); // sunday of the same week as date
 
         let body = ProjectsForEmployeeRequest {
@@ -163,18 +175,12 @@ impl HttpClient {
         .serialize(serde_json::value::Serializer)?
         .to_string();
 
-        let url = format!(
-            "{}/rpc/projects_info_for_employee_in_period",
-            floq_api_domain()
-        );
-        let mut response: Response = surf::post(url)
-            .header("Content-Type", "application/json")
-            .header("Accept", "application/json")
-            .header("Authorization", format!("Bearer {}", self.access_token))
-            .body(body)
-            .send()
+        let mut response: Response = self
+            .send_read(|| {
+                self.post("rpc/projects_info_for_employee_in_period")
+                    .body(body.as_str())
+            })
             .await
-            .handle_floq_response()
             .with_context(|| "Noe gikk galt under henting av dine prosjekter")?;
 
         let projects: Vec<ProjectForEmployeeResponse> = response.body_json()
@@ -185,3 +191,67 @@ impl HttpClient {
         Ok(projects.into_iter().map(|r| r.into_project()).collect())
     }
 }
+
+#[cfg(test)]
+demonstrate::demonstrate! {
+    describe "projects" {
+        use super::*;
+        use crate::api::fake::FakeFloqApi;
+        use async_std::task;
+
+        before {
+            let today = Utc::now().date().naive_local();
+            let api = FakeFloqApi::ola()
+                .with_project("KUN1000", "Nettbutikk", "Kunde")
+                .with_project("BLA1000", "Internt", "Blank")
+                .with_project("KUN2000", "App", "Kunde")
+                .with_time_entry("KUN2000", today, Duration::hours(2))
+                .with_time_entry("BLA1000", today - Duration::weeks(3), Duration::hours(2));
+            let preferences = user::Preferences::default();
+            let mut out = Vec::new();
+        }
+
+        after {
+            let output = String::from_utf8(out).unwrap();
+            let lines: Vec<&str> = output.lines().map(str::trim_end).collect();
+            assert_eq!(lines, expected);
+        }
+
+        it "lists all projects sorted by id" {
+            let matches = subcommand_app().get_matches_from(vec![SUBCOMMAND_NAME, "--alle"]);
+            task::block_on(execute(&matches, &mut out, &api, &preferences)).unwrap();
+
+            let expected = vec![
+                "ID           KUNDE      BESKRIVELSE",
+                "BLA1000      Blank      Internt",
+                "KUN1000      Kunde      Nettbutikk",
+                "KUN2000      Kunde      App",
+            ];
+        }
+
+        it "lists the projects stamped on in the last two weeks" {
+            let matches = subcommand_app().get_matches_from(vec![SUBCOMMAND_NAME]);
+            task::block_on(execute(&matches, &mut out, &api, &preferences)).unwrap();
+
+            let expected = vec![
+                "ID           KUNDE      BESKRIVELSE",
+                "KUN2000      Kunde      App",
+            ];
+        }
+
+        it "looks as many weeks back as preferred" {
+            let preferences = user::Preferences {
+                history_weeks: 4,
+                ..preferences
+            };
+            let matches = subcommand_app().get_matches_from(vec![SUBCOMMAND_NAME]);
+            task::block_on(execute(&matches, &mut out, &api, &preferences)).unwrap();
+
+            let expected = vec![
+                "ID           KUNDE      BESKRIVELSE",
+                "BLA1000      Blank      Internt",
+                "KUN2000      Kunde      App",
+            ];
+        }
+    }
+}
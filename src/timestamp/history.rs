@@ -1,9 +1,16 @@
 use super::{TimestampDate, TimestampHours};
-use crate::{cmd::Subcommand, http_client::HttpClient, print, user};
+use crate::{
+    api::FloqApi,
+    cmd::{self, Subcommand},
+    error::FloqError,
+    http_client::HttpClient,
+    print,
+    user::{self, WeekendDisplay},
+};
 
 use std::{collections::HashMap, io::Write};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use async_trait::async_trait;
 use chrono::{Datelike, Duration, NaiveDate, Utc, Weekday};
 use clap::{App, Arg, ArgMatches};
@@ -92,13 +99,15 @@ impl<T: Write + Send> Subcommand<T> for TimestampHistorySubcommand {
     }
 
     async fn execute(&self, matches: &ArgMatches, out: &mut T) -> Result<()> {
-        let user = user::load_user_from_config(out).await?;
-        let client = HttpClient::from_user(&user);
+        let (user, preferences) = user::load_user_and_preferences(matches, out).await?;
+        let client =
+            HttpClient::from_user(&user)?.with_retry(cmd::retry_policy(matches, &preferences));
 
-        execute(matches, out, client).await
+        execute(matches, out, &client, &preferences).await
     }
 }
 
+/// Time stamped on a date.
 #[derive(Debug, PartialEq, Eq, Hash)]
 pub struct Timestamp {
     pub date: NaiveDate,
@@ -111,6 +120,7 @@ impl Timestamp {
     }
 }
 
+/// Time stamped on a project on a date.
 pub struct ProjectTimestamp {
     pub project_id: String,
     pub project_name: String,
@@ -129,6 +139,7 @@ impl ProjectTimestamp {
     }
 }
 
+/// Time stamped on a project on several dates.
 #[derive(Debug, PartialEq, Eq, Hash)]
 pub struct ProjectTimestamps {
     pub project_id: String,
@@ -146,15 +157,21 @@ impl ProjectTimestamps {
 async fn execute<T: Write + Send>(
     matches: &ArgMatches,
     out: &mut T,
-    client: HttpClient,
+    api: &dyn FloqApi,
+    preferences: &user::Preferences,
 ) -> Result<()> {
     if matches.is_present("dato") {
-        let date = matches.value_of("dato").unwrap().parse()?;
+        let date = matches.value_of("dato").unwrap().parse().with_context(|| {
+            FloqError::Validation(
+                "Dato må være i formatet YYYY-MM-DD, f.eks. 2021-03-01".to_string(),
+            )
+        })?;
 
-        let mut timestamps = client.get_timestamps_for_date(date).await?;
+        let mut timestamps = api.get_timestamps_for_date(date).await?;
         timestamps.sort_by(|t0, t1| t0.project_id.cmp(&t1.project_id));
 
         let mut table_maker = print::TableMaker::new();
+        table_maker.style(preferences.table_style);
 
         table_maker.titles(vec![
             "PROSJEKT".to_string(),
@@ -169,7 +186,11 @@ async fn execute<T: Write + Send>(
         table_maker.into_table(timestamps.as_slice()).print(out)?;
     } else {
         let from = if let Some(from) = matches.value_of("fra") {
-            from.parse::<NaiveDate>()?
+            from.parse::<NaiveDate>().with_context(|| {
+                FloqError::Validation(
+                    "Fra dato må være i formatet YYYY-MM-DD, f.eks. 2021-03-01".to_string(),
+                )
+            })?
         } else {
             let base_date = if matches.is_present("forrige-uke") {
                 Utc::now().date() - Duration::weeks(1)
@@ -185,7 +206,11 @@ async fn execute<T: Write + Send>(
         };
 
         let to = if let Some(to) = matches.value_of("til") {
-            to.parse::<NaiveDate>()?
+            to.parse::<NaiveDate>().with_context(|| {
+                FloqError::Validation(
+                    "Til dato må være i formatet YYYY-MM-DD, f.eks. 2021-03-01".to_string(),
+                )
+            })?
         } else {
             let base_date = if matches.is_present("forrige-uke") {
                 Utc::now().date() - Duration::weeks(1)
@@ -197,7 +222,7 @@ async fn execute<T: Write + Send>(
             };
             let days_from_monday = base_date.weekday().num_days_from_monday() as i64;
 
-            base_date.naive_local() + Duration::days(6 - days_from_monday as i64)
+            base_date.naive_local() + Duration::days(6 - days_from_monday)
         };
 
         // only one of these two can be true, if none are then we let the number of days in period decide
@@ -205,10 +230,11 @@ async fn execute<T: Write + Send>(
         let dont_turn_table = matches.is_present("ikke-snu-tabell");
         if turn_table || (!dont_turn_table && to - from > Duration::days(6)) {
             // auto transpose if more than one week
-            let mut timestamps = client.get_timestamps_for_period(from, to).await?;
-            timestamps.sort_by(|t0, t1| t0.timestamp.date.cmp(&t1.timestamp.date));
+            let mut timestamps = api.get_timestamps_for_period(from, to).await?;
+            timestamps.sort_by_key(|t| t.timestamp.date);
 
             let mut table_maker = print::TableMaker::new();
+            table_maker.style(preferences.table_style);
             table_maker.static_titles(vec!["DATO", "PROSJEKT", "TIMER"]);
             table_maker.with(Box::new(|pt: &ProjectTimestamp| {
                 TimestampDate(&pt.timestamp.date).to_string()
@@ -220,7 +246,7 @@ async fn execute<T: Write + Send>(
 
             table_maker.into_table(timestamps.as_slice()).print(out)?;
         } else {
-            let mut timestamps = get_timestamps_for_period(client, from, to).await?;
+            let mut timestamps = get_timestamps_for_period(api, from, to).await?;
             timestamps.sort_by(|t0, t1| t0.project_id.cmp(&t1.project_id));
             let timestamped_dates: HashMap<NaiveDate, ()> = timestamps
                 .iter()
@@ -230,14 +256,20 @@ async fn execute<T: Write + Send>(
             let mut skipped_days = vec![];
 
             let mut table_maker = print::TableMaker::new();
+            table_maker.style(preferences.table_style);
 
             let titles = from.iter_days().take_while(|d| d <= &to).fold(
                 vec!["PROSJEKT".to_string()],
                 |mut titles, next| {
-                    // skip days in weekend if no timestamp
+                    // skip days in weekend unless preferred otherwise
                     let weekday = next.weekday();
                     let is_weekend = weekday == Weekday::Sat || weekday == Weekday::Sun;
-                    if !is_weekend || timestamped_dates.contains_key(&next) {
+                    let show_weekend = match preferences.weekend {
+                        WeekendDisplay::Auto => timestamped_dates.contains_key(&next),
+                        WeekendDisplay::Always => true,
+                        WeekendDisplay::Never => false,
+                    };
+                    if !is_weekend || show_weekend {
                         titles.push(TimestampDate(&next).to_string());
                     } else {
                         skipped_days.push(next);
@@ -266,12 +298,13 @@ async fn execute<T: Write + Send>(
     Ok(())
 }
 
+/// Time stamped from `from` to `to`, both inclusive, grouped by project.
 pub async fn get_timestamps_for_period(
-    client: HttpClient,
+    api: &dyn FloqApi,
     from: NaiveDate,
     to: NaiveDate,
 ) -> Result<Vec<ProjectTimestamps>> {
-    let project_timestamps = client.get_timestamps_for_period(from, to).await?;
+    let project_timestamps = api.get_timestamps_for_period(from, to).await?;
 
     let project_to_timestamps: HashMap<String, ProjectTimestamps> = project_timestamps
         .into_iter()
@@ -289,5 +322,119 @@ pub async fn get_timestamps_for_period(
             res
         });
 
-    Ok(project_to_timestamps.into_iter().map(|(_k, v)| v).collect())
+    Ok(project_to_timestamps.into_values().collect())
+}
+
+#[cfg(test)]
+demonstrate::demonstrate! {
+    describe "history" {
+        use super::*;
+        use crate::api::fake::FakeFloqApi;
+        use async_std::task;
+
+        before {
+            let monday = NaiveDate::from_ymd(2021, 3, 1);
+            let api = FakeFloqApi::ola()
+                .with_project("KUN1000", "Nettbutikk", "Kunde")
+                .with_project("BLA1000", "Internt", "Blank")
+                .with_time_entry("KUN1000", monday, Duration::hours(4))
+                .with_time_entry("BLA1000", monday, Duration::minutes(210))
+                .with_time_entry("KUN1000", monday.succ(), Duration::minutes(450));
+            let preferences = user::Preferences::default();
+            let mut out = Vec::new();
+        }
+
+        after {
+            let output = String::from_utf8(out).unwrap();
+            let lines: Vec<&str> = output.lines().map(str::trim_end).collect();
+            assert_eq!(lines, expected);
+        }
+
+        it "shows the hours of a date" {
+            let matches = subcommand_app()
+                .get_matches_from(vec![SUBCOMMAND_NAME, "--dato", "2021-03-01"]);
+            task::block_on(execute(&matches, &mut out, &api, &preferences)).unwrap();
+
+            let expected = vec![
+                "PROSJEKT      2021-03-01 (man)",
+                "BLA1000       3.5t",
+                "KUN1000       4.0t",
+            ];
+        }
+
+        it "shows a week without the weekend" {
+            let matches = subcommand_app().get_matches_from(vec![
+                SUBCOMMAND_NAME,
+                "--fra",
+                "2021-03-01",
+                "--til",
+                "2021-03-07",
+            ]);
+            task::block_on(execute(&matches, &mut out, &api, &preferences)).unwrap();
+
+            let expected = vec![
+                "PROSJEKT      2021-03-01 (man)      2021-03-02 (tir)      2021-03-03 (ons)      2021-03-04 (tor)      2021-03-05 (fre)",
+                "BLA1000       3.5t",
+                "KUN1000       4.0t                  7.5t",
+            ];
+        }
+
+        it "shows stamped weekend days" {
+            let saturday = NaiveDate::from_ymd(2021, 3, 6);
+            let api = api.with_time_entry("KUN1000", saturday, Duration::hours(1));
+            let matches = subcommand_app().get_matches_from(vec![
+                SUBCOMMAND_NAME,
+                "--fra",
+                "2021-03-01",
+                "--til",
+                "2021-03-07",
+            ]);
+            task::block_on(execute(&matches, &mut out, &api, &preferences)).unwrap();
+
+            let expected = vec![
+                "PROSJEKT      2021-03-01 (man)      2021-03-02 (tir)      2021-03-03 (ons)      2021-03-04 (tor)      2021-03-05 (fre)      2021-03-06 (lør)",
+                "BLA1000       3.5t",
+                "KUN1000       4.0t                  7.5t                                                                                    1.0t",
+            ];
+        }
+
+        it "shows the weekend when preferred" {
+            let preferences = user::Preferences {
+                weekend: WeekendDisplay::Always,
+                ..preferences
+            };
+            let matches = subcommand_app().get_matches_from(vec![
+                SUBCOMMAND_NAME,
+                "--fra",
+                "2021-03-01",
+                "--til",
+                "2021-03-07",
+            ]);
+            task::block_on(execute(&matches, &mut out, &api, &preferences)).unwrap();
+
+            let expected = vec![
+                "PROSJEKT      2021-03-01 (man)      2021-03-02 (tir)      2021-03-03 (ons)      2021-03-04 (tor)      2021-03-05 (fre)      2021-03-06 (lør)      2021-03-07 (søn)",
+                "BLA1000       3.5t",
+                "KUN1000       4.0t                  7.5t",
+            ];
+        }
+
+        it "turns the table for more than a week" {
+            let matches = subcommand_app().get_matches_from(vec![
+                SUBCOMMAND_NAME,
+                "--fra",
+                "2021-03-01",
+                "--til",
+                "2021-03-14",
+            ]);
+            task::block_on(execute(&matches, &mut out, &api, &preferences)).unwrap();
+
+            let expected = vec![
+                "DATO                  PROSJEKT      TIMER",
+                "2021-03-01 (man)      KUN1000       4.0t",
+                "2021-03-01 (man)      BLA1000       3.5t",
+                "2021-03-02 (tir)      KUN1000       7.5t",
+            ];
+        }
+    }
 }
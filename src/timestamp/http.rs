@@ -1,6 +1,11 @@
 use super::history::{ProjectTimestamp, Timestamp};
-use crate::http_client::floq_api_domain;
-use crate::http_client::{HandleInvalidToken, HandleMalformedBody, HttpClient};
+use crate::{
+    error::FloqError,
+    http_client::{HandleMalformedBody, HttpClient},
+    trace::{self, Level},
+};
+
+use std::collections::BTreeMap;
 
 use anyhow::{anyhow, Context, Result};
 use chrono::{Duration, NaiveDate};
@@ -13,6 +18,25 @@ struct TimeEntry {
     minutes: i64,
 }
 
+#[derive(Deserialize, Debug)]
+struct PeriodTimeEntry {
+    date: NaiveDate,
+    minutes: i64,
+    project: PeriodProject,
+}
+
+#[derive(Deserialize, Debug)]
+struct PeriodProject {
+    id: String,
+    name: String,
+    customer: PeriodCustomer,
+}
+
+#[derive(Deserialize, Debug)]
+struct PeriodCustomer {
+    name: String,
+}
+
 #[derive(Serialize, Debug)]
 struct TimestampedProjectsRequest {
     employee_id: u16,
@@ -42,25 +66,21 @@ impl TimestampedProjectsResponse {
 }
 
 impl HttpClient {
+    /// Time stamped on the project on the date.
     pub async fn get_timestamp_on_project_for_date(
         &self,
         project_id: &str,
         date: &NaiveDate,
     ) -> Result<Duration> {
-        let url = format!(
-            "{}/time_entry?select=minutes&employee=eq.{}&project=eq.{}&date=eq.{}",
-            floq_api_domain(),
+        let path = format!(
+            "time_entry?select=minutes&employee=eq.{}&project=eq.{}&date=eq.{}",
             self.employee_id,
             project_id,
             date.format("%Y-%m-%d"),
         );
-        let mut response: Response = surf::get(url)
-            .header("Content-Type", "application/json")
-            .header("Accept", "application/json")
-            .header("Authorization", format!("Bearer {}", self.access_token))
-            .send()
+        let mut response: Response = self
+            .send(|| self.get(&path))
             .await
-            .handle_floq_response()
             .with_context(|| "Noe gikk galt under henting av dine timer for et prosjekt")?;
 
         let minutes = response
@@ -75,10 +95,95 @@ impl HttpClient {
         Ok(Duration::minutes(minutes))
     }
 
+    /// Time stamped on each project for every day from `from` to `to`, both inclusive, leaving
+    /// out days without any time. Fetched with one query, or one request per day if Floq won't
+    /// answer the query.
     pub async fn get_timestamps_for_period(
         &self,
         from: NaiveDate,
         to: NaiveDate,
+    ) -> Result<Vec<ProjectTimestamp>> {
+        match self.get_timestamps_for_period_in_one_query(from, to).await {
+            Err(e) if should_fetch_by_day(&e) => {
+                if trace::enabled(Level::Requests) {
+                    eprintln!(
+                        "Klarte ikke å hente timene for perioden fra /time_entry, henter en dag om gangen: {:#}",
+                        e
+                    );
+                }
+                self.get_timestamps_for_period_by_day(from, to).await
+            }
+            result => result,
+        }
+    }
+
+    /// Fetched a page at a time, as PostgREST may be set up to answer with at most `max-rows`
+    /// rows. The total PostgREST counts in `Content-Range` tells when every row is fetched.
+    async fn get_timestamps_for_period_in_one_query(
+        &self,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> Result<Vec<ProjectTimestamp>> {
+        let mut entries: Vec<PeriodTimeEntry> = vec![];
+        loop {
+            let path = format!(
+                "time_entry?select=date,minutes,project{{id,name,customer{{name}}}}&employee=eq.{}&date=gte.{}&date=lte.{}&order=date,id&offset={}",
+                self.employee_id,
+                from.format("%Y-%m-%d"),
+                to.format("%Y-%m-%d"),
+                entries.len(),
+            );
+            let mut response: Response = self
+                .send(|| self.get(&path).header("Prefer", "count=exact"))
+                .await
+                .with_context(|| "Noe gikk galt under henting av dine timer for perioden")?;
+
+            let total = response
+                .header("Content-Range")
+                .and_then(|range| total_of_content_range(range.last().as_str()));
+            let page = response
+                .body_json::<Vec<PeriodTimeEntry>>()
+                .await
+                .handle_malformed_body()
+                .with_context(|| "Klarte ikke lese responsen fra /time_entry")?;
+
+            // without a total, e.g. from a proxy dropping the header, pages are fetched until one
+            // is empty
+            let done = page.is_empty() || total.is_some_and(|t| entries.len() + page.len() >= t);
+            entries.extend(page);
+            if done {
+                break;
+            }
+        }
+
+        // a project may have several entries on a date, which are summed like Floq does
+        let mut summed: BTreeMap<(String, NaiveDate), ProjectTimestamp> = BTreeMap::new();
+        for entry in entries {
+            let timestamp = &mut summed
+                .entry((entry.project.id.clone(), entry.date))
+                .or_insert_with(|| ProjectTimestamp {
+                    project_id: entry.project.id,
+                    project_name: entry.project.name,
+                    customer_name: entry.project.customer.name,
+                    timestamp: Timestamp {
+                        date: entry.date,
+                        time: Duration::zero(),
+                    },
+                })
+                .timestamp;
+            timestamp.time = timestamp.time + Duration::minutes(entry.minutes);
+        }
+
+        Ok(summed
+            .into_values()
+            .filter(|tp| !tp.timestamp.is_time_zero())
+            .collect())
+    }
+
+    async fn get_timestamps_for_period_by_day(
+        &self,
+        from: NaiveDate,
+        to: NaiveDate,
     ) -> Result<Vec<ProjectTimestamp>> {
         let difference = to.signed_duration_since(from).num_days();
 
@@ -94,6 +199,7 @@ impl HttpClient {
         Ok(results.into_iter().flatten().collect())
     }
 
+    /// Time stamped on each project on the date, leaving out projects without any time.
     pub async fn get_timestamps_for_date(&self, date: NaiveDate) -> Result<Vec<ProjectTimestamp>> {
         let body = TimestampedProjectsRequest {
             employee_id: self.employee_id,
@@ -102,15 +208,12 @@ impl HttpClient {
         .serialize(serde_json::value::Serializer)?
         .to_string();
 
-        let url = format!("{}/rpc/projects_for_employee_for_date", floq_api_domain());
-        let mut response: Response = surf::post(url)
-            .body(body)
-            .header("Content-Type", "application/json")
-            .header("Accept", "application/json")
-            .header("Authorization", format!("Bearer {}", self.access_token))
-            .send()
+        let mut response: Response = self
+            .send_read(|| {
+                self.post("rpc/projects_for_employee_for_date")
+                    .body(body.as_str())
+            })
             .await
-            .handle_floq_response()
             .with_context(|| "Noe gikk galt under henting av dine timer for en dag")?;
 
         let response: Vec<TimestampedProjectsResponse> = response
@@ -139,6 +242,8 @@ struct TimestampRequest<'a> {
 }
 
 impl HttpClient {
+    /// Adds `time` to what's already stamped on the project on the date, negative time removes
+    /// time.
     pub async fn add_timestamp(
         &self,
         project_id: &str,
@@ -155,21 +260,166 @@ impl HttpClient {
         .serialize(serde_json::value::Serializer)?
         .to_string();
 
-        let response = surf::post(format!("{}/time_entry", floq_api_domain()))
-            .body(body)
-            .header("Content-Type", "application/json")
-            .header("Authorization", format!("Bearer {}", self.access_token))
-            .send()
+        let response = self
+            .send(|| self.post("time_entry").body(body.as_str()))
             .await
-            .handle_floq_response()
             .with_context(|| "Noe gikk galt under føring av timer")?;
 
+        // error responses are already turned into a FloqError by `send`, so this is a success
+        // Floq isn't expected to respond with
         match response.status() {
             StatusCode::Created => Ok(()),
-            sc => Err(anyhow!(
+            sc => Err(anyhow!(FloqError::MalformedBody).context(format!(
                 "Fikk en annen statuskode enn forventet fra POST /time_entry {}",
                 sc
-            )),
+            ))),
+        }
+    }
+}
+
+/// The total number of rows in a `Content-Range` like `0-999/1500`, or `*/0` when there are none.
+/// PostgREST only counts them when asked to with `Prefer: count=exact`.
+fn total_of_content_range(range: &str) -> Option<usize> {
+    range.rsplit_once('/')?.1.trim().parse().ok()
+}
+
+/// Whether the query for a whole period was refused by Floq, in which case the same time entries
+/// are likely still available one day at a time. Errors reaching Floq are not retried this way.
+fn should_fetch_by_day(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<FloqError>(),
+        Some(FloqError::Validation(_))
+            | Some(FloqError::NotFound)
+            | Some(FloqError::Forbidden)
+            | Some(FloqError::MalformedBody)
+    )
+}
+
+#[cfg(test)]
+demonstrate::demonstrate! {
+    describe "get_timestamps_for_period" {
+        use super::*;
+        use crate::{
+            http_client::RetryPolicy,
+            user::{profile::Profile, CredentialSource, User},
+        };
+        use async_std::task;
+        use serde_json::json;
+        use std::{
+            sync::{
+                atomic::{AtomicUsize, Ordering},
+                mpsc, Arc, Mutex,
+            },
+            time::Duration as StdDuration,
+        };
+
+        before {
+            // a Floq answering with at most 2 time entries at a time, without Content-Range as
+            // tiny_http won't send it, or refusing the query for the period with the status and
+            // body in `refusal`
+            let refusal: Arc<Mutex<Option<(u16, &'static str)>>> = Arc::new(Mutex::new(None));
+            let queries = Arc::new(AtomicUsize::new(0));
+            let (served_refusal, counted) = (refusal.clone(), queries.clone());
+            let (tx, rx) = mpsc::channel();
+            std::thread::spawn(move || {
+                let server = rouille::Server::new("127.0.0.1:0", move |request| {
+                    if request.url() == "/rpc/projects_for_employee_for_date" {
+                        return rouille::Response::json(&json!([
+                            { "id": "KUN1000", "project": "Nettbutikk", "customer": "Kunde AS", "minutes": 60 },
+                        ]));
+                    }
+                    counted.fetch_add(1, Ordering::SeqCst);
+                    if let Some((status, body)) = *served_refusal.lock().unwrap() {
+                        return rouille::Response::text(body).with_status_code(status);
+                    }
+
+                    let project = |id, name| json!({ "id": id, "name": name, "customer": { "name": "Kunde AS" } });
+                    let rows = [
+                        json!({ "date": "2021-03-01", "minutes": 450, "project": project("KUN1000", "Nettbutikk") }),
+                        json!({ "date": "2021-03-01", "minutes": 30, "project": project("KUN1000", "Nettbutikk") }),
+                        json!({ "date": "2021-03-02", "minutes": 210, "project": project("BLA1000", "Internt") }),
+                        json!({ "date": "2021-03-02", "minutes": 0, "project": project("BLA1001", "Ferie") }),
+                        json!({ "date": "2021-03-02", "minutes": 60, "project": project("KUN1000", "Nettbutikk") }),
+                    ];
+                    let offset: usize = request.get_param("offset").unwrap().parse().unwrap();
+                    let page: Vec<_> = rows.iter().skip(offset).take(2).collect();
+                    rouille::Response::json(&page)
+                })
+                .unwrap();
+                tx.send(server.server_addr()).unwrap();
+                server.run();
+            });
+            let domain = format!("http://{}", rx.recv().unwrap());
+
+            let client = HttpClient::from_user(&User {
+                employee_id: 1,
+                email: "ola@blank.no".to_string(),
+                name: "Ola Nordmann".to_string(),
+                access_token: "token".to_string(),
+                refresh_token: None,
+                profile: Profile {
+                    name: "test".to_string(),
+                    domain: domain.clone(),
+                    api_domain: domain,
+                    ca_certificates: None,
+                },
+                source: CredentialSource::Environment,
+            })
+            .unwrap()
+            .with_retry(RetryPolicy {
+                retries: 0,
+                delay: StdDuration::from_millis(1),
+            });
+            let from = NaiveDate::from_ymd(2021, 3, 1);
+            let to = NaiveDate::from_ymd(2021, 3, 2);
+        }
+
+        it "sums every page of entries by project and date" {
+            let timestamps = task::block_on(client.get_timestamps_for_period(from, to)).unwrap();
+
+            let summed: Vec<_> = timestamps
+                .iter()
+                .map(|tp| (tp.project_id.as_str(), tp.timestamp.date, tp.timestamp.time.num_minutes()))
+                .collect();
+            assert_eq!(
+                summed,
+                vec![
+                    ("BLA1000", to, 210),
+                    ("KUN1000", from, 480),
+                    ("KUN1000", to, 60),
+                ]
+            );
+            // the last page is empty
+            assert_eq!(queries.load(Ordering::SeqCst), 4);
+        }
+
+        it "fetches one day at a time when the query for the period is refused" {
+            let refusals = [
+                (400, r#"{"code":"PGRST100","message":"failed to parse"}"#),
+                (403, ""),
+                (404, ""),
+                (200, "ikke json"),
+            ];
+            for refused in refusals {
+                *refusal.lock().unwrap() = Some(refused);
+
+                let timestamps = task::block_on(client.get_timestamps_for_period(from, to)).unwrap();
+
+                let mut days: Vec<_> = timestamps.iter().map(|tp| tp.timestamp.date).collect();
+                days.sort();
+                assert_eq!(days, vec![from, to], "refused with {:?}", refused);
+            }
+            assert_eq!(queries.load(Ordering::SeqCst), refusals.len());
+        }
+    }
+
+    describe "total_of_content_range" {
+        use super::*;
+
+        it "reads the total counted by PostgREST" {
+            assert_eq!(total_of_content_range("0-999/1500"), Some(1500));
+            assert_eq!(total_of_content_range("*/0"), Some(0));
+            assert_eq!(total_of_content_range("0-24/*"), None);
         }
     }
 }
@@ -1,4 +1,10 @@
-use crate::{cmd::Subcommand, http_client::HttpClient, time, user};
+use crate::{
+    api::FloqApi,
+    cmd::{self, Subcommand},
+    error::FloqError,
+    http_client::HttpClient,
+    time, user,
+};
 
 use std::{fmt::Display, io::Write};
 
@@ -39,15 +45,17 @@ pub fn subcommand_app<'help>() -> App<'help> {
     App::new(SUBCOMMAND_NAME)
         .about("Før timer på et prosjekt")
         .setting(AppSettings::SubcommandsNegateReqs)
-        .arg(Arg::new("prosjekt").about("Prosjektet du ønsker å føre timer på").required(true).index(1))
+        .arg(
+            Arg::new("prosjekt")
+                .about("Prosjektet du ønsker å føre timer på, settes til \"prosjekt\" i preferansene hvis utelatt")
+                .index(1)
+        )
         .arg(
             Arg::new("timer")
                 .long("timer")
                 .short('t')
                 .takes_value(true)
-                .default_value("7.5")
-                .hide_default_value(true)
-                .about("Antall timer du ønsker å føre, settes til \"7.5\" hvis utelatt")
+                .about("Antall timer du ønsker å føre, settes til \"timer\" i preferansene eller \"7.5\" hvis utelatt")
         )
         .arg(
             Arg::new("dato")
@@ -110,10 +118,11 @@ impl<T: Write + Send> Subcommand<T> for TimestampSubcommand {
     }
 
     async fn execute(&self, matches: &ArgMatches, out: &mut T) -> Result<()> {
-        let user = user::load_user_from_config(out).await?;
-        let client = HttpClient::from_user(&user);
+        let (user, preferences) = user::load_user_and_preferences(matches, out).await?;
+        let client =
+            HttpClient::from_user(&user)?.with_retry(cmd::retry_policy(matches, &preferences));
 
-        execute(matches, out, client).await
+        execute(matches, out, &client, &preferences).await
     }
 }
 
@@ -138,7 +147,7 @@ impl<'a> Display for TimestampDate<'a> {
         write!(
             f,
             "{} ({})",
-            self.0.format("%Y-%m-%d").to_string(),
+            self.0.format("%Y-%m-%d"),
             weekdays.get_weekday().short_name
         )
     }
@@ -154,17 +163,31 @@ struct SetTimestampResult<'a> {
 async fn execute<T: Write + Send>(
     matches: &ArgMatches,
     out: &mut T,
-    client: HttpClient,
+    api: &dyn FloqApi,
+    preferences: &user::Preferences,
 ) -> Result<()> {
-    let project_id = matches.value_of("prosjekt").unwrap();
+    let project_id = matches
+        .value_of("prosjekt")
+        .or(preferences.default_project.as_deref())
+        .ok_or_else(|| {
+            anyhow!(FloqError::Validation(
+                "Du må velge et prosjekt, eller sette \"prosjekt\" under [preferanser] i konfigurasjonen"
+                    .to_string()
+            ))
+        })?;
 
-    let hours: f32 = matches.value_of("timer").unwrap().parse()?;
+    let hours: f32 = match matches.value_of("timer") {
+        Some(hours) => hours.parse().with_context(|| {
+            FloqError::Validation("Timer må være et tall, f.eks. 7.5".to_string())
+        })?,
+        None => preferences.default_hours,
+    };
     let time = Duration::minutes((hours * 60.0) as i64);
     if time > Duration::days(1) {
-        return Err(anyhow!(
+        return Err(anyhow!(FloqError::Validation(format!(
             "Det er ikke mulig å føre {} timer på én dag",
             hours
-        ));
+        ))));
     }
 
     let dates = if matches.is_present("fra") {
@@ -172,20 +195,31 @@ async fn execute<T: Write + Send>(
             .value_of("fra")
             .unwrap()
             .parse::<NaiveDate>()
-            .with_context(|| "Fra dato må være i formatet YYYY-MM-DD, f.eks. 2021-03-01")?;
+            .with_context(|| {
+                FloqError::Validation(
+                    "Fra dato må være i formatet YYYY-MM-DD, f.eks. 2021-03-01".to_string(),
+                )
+            })?;
         let to: NaiveDate = matches
             .value_of("til")
             .unwrap()
             .parse::<NaiveDate>()
-            .with_context(|| "Til dato må være i formatet YYYY-MM-DD, f.eks. 2021-03-01")?;
+            .with_context(|| {
+                FloqError::Validation(
+                    "Til dato må være i formatet YYYY-MM-DD, f.eks. 2021-03-01".to_string(),
+                )
+            })?;
 
         from.iter_days().take_while(|d| d <= &to).collect()
     } else if matches.is_present("dato") {
         let date: NaiveDate = matches
             .value_of("dato")
             .map(|date| {
-                date.parse::<NaiveDate>()
-                    .with_context(|| "Dato må være i formatet YYYY-MM-DD, f.eks. 2021-03-01")
+                date.parse::<NaiveDate>().with_context(|| {
+                    FloqError::Validation(
+                        "Dato må være i formatet YYYY-MM-DD, f.eks. 2021-03-01".to_string(),
+                    )
+                })
             })
             .unwrap_or_else(|| Ok(Utc::now().date().naive_local()))?;
 
@@ -220,7 +254,7 @@ async fn execute<T: Write + Send>(
 
     let mut futures: FuturesUnordered<_> = dates
         .iter()
-        .map(|date| set_timetsamp(project_id, &time, date, &client))
+        .map(|date| set_timetsamp(project_id, &time, date, api))
         .collect();
     while let Some(r) = futures.next().await {
         let set_timestamp_result = r?;
@@ -250,16 +284,15 @@ async fn set_timetsamp<'a>(
     project_id: &'a str,
     time: &'a Duration,
     date: &'a NaiveDate,
-    client: &HttpClient,
+    api: &dyn FloqApi,
 ) -> Result<SetTimestampResult<'a>> {
-    let current_time = client
+    let current_time = api
         .get_timestamp_on_project_for_date(project_id, date)
         .await?;
     let time_diff = *time - current_time;
 
     if !time_diff.is_zero() {
-        client
-            .add_timestamp(project_id, date, time_diff)
+        api.add_timestamp(project_id, date, time_diff)
             .await
             .map(|_| ())?;
     }
@@ -271,3 +304,194 @@ async fn set_timetsamp<'a>(
         time_diff,
     })
 }
+
+#[cfg(test)]
+demonstrate::demonstrate! {
+    describe "timestamp" {
+        use super::*;
+        use crate::api::fake::{FakeFloqApi, TimeEntry};
+        use async_std::task;
+
+        before {
+            let monday = NaiveDate::from_ymd(2021, 3, 1);
+            let api = FakeFloqApi::ola()
+                .with_project("KUN1000", "Nettbutikk", "Kunde")
+                .with_project("BLA1000", "Internt", "Blank")
+                .with_time_entry("KUN1000", monday, Duration::hours(4));
+            let preferences = user::Preferences::default();
+            let mut out = Vec::new();
+        }
+
+        context "stamping" {
+            after {
+                let output = String::from_utf8(out).unwrap();
+                let mut lines: Vec<&str> = output.lines().collect();
+                // the days of a period are stamped concurrently
+                lines.sort_unstable();
+                assert_eq!(lines, expected_lines);
+                assert_eq!(api.time_entries(), expected_entries);
+            }
+
+            it "stamps a full day by default" {
+                let matches = subcommand_app()
+                    .get_matches_from(vec![SUBCOMMAND_NAME, "BLA1000", "--dato", "2021-03-01"]);
+                task::block_on(execute(&matches, &mut out, &api, &preferences)).unwrap();
+
+                let expected_lines = vec!["Førte 7.5t på BLA1000 for 2021-03-01 (man)"];
+                let expected_entries = vec![
+                    TimeEntry {
+                        project_id: "KUN1000".to_string(),
+                        date: monday,
+                        time: Duration::hours(4),
+                    },
+                    TimeEntry {
+                        project_id: "BLA1000".to_string(),
+                        date: monday,
+                        time: Duration::minutes(450),
+                    },
+                ];
+            }
+
+            it "only adds the difference to what is already stamped" {
+                let matches = subcommand_app().get_matches_from(vec![
+                    SUBCOMMAND_NAME,
+                    "KUN1000",
+                    "--dato",
+                    "2021-03-01",
+                    "--timer",
+                    "6",
+                ]);
+                task::block_on(execute(&matches, &mut out, &api, &preferences)).unwrap();
+
+                let expected_lines = vec!["Førte 6.0t på KUN1000 for 2021-03-01 (man)"];
+                let expected_entries = vec![
+                    TimeEntry {
+                        project_id: "KUN1000".to_string(),
+                        date: monday,
+                        time: Duration::hours(4),
+                    },
+                    TimeEntry {
+                        project_id: "KUN1000".to_string(),
+                        date: monday,
+                        time: Duration::hours(2),
+                    },
+                ];
+            }
+
+            it "does nothing when the hours are already stamped" {
+                let matches = subcommand_app().get_matches_from(vec![
+                    SUBCOMMAND_NAME,
+                    "KUN1000",
+                    "--dato",
+                    "2021-03-01",
+                    "--timer",
+                    "4",
+                ]);
+                task::block_on(execute(&matches, &mut out, &api, &preferences)).unwrap();
+
+                let expected_lines =
+                    vec!["Du har allerede ført 4.0t på KUN1000 for 2021-03-01 (man)"];
+                let expected_entries = vec![TimeEntry {
+                    project_id: "KUN1000".to_string(),
+                    date: monday,
+                    time: Duration::hours(4),
+                }];
+            }
+
+            it "uses the preferred project and hours" {
+                let preferences = user::Preferences {
+                    default_hours: 3.0,
+                    default_project: Some("BLA1000".to_string()),
+                    ..preferences
+                };
+                let matches = subcommand_app()
+                    .get_matches_from(vec![SUBCOMMAND_NAME, "--dato", "2021-03-02"]);
+                task::block_on(execute(&matches, &mut out, &api, &preferences)).unwrap();
+
+                let expected_lines = vec!["Førte 3.0t på BLA1000 for 2021-03-02 (tir)"];
+                let expected_entries = vec![
+                    TimeEntry {
+                        project_id: "KUN1000".to_string(),
+                        date: monday,
+                        time: Duration::hours(4),
+                    },
+                    TimeEntry {
+                        project_id: "BLA1000".to_string(),
+                        date: monday.succ(),
+                        time: Duration::hours(3),
+                    },
+                ];
+            }
+
+            it "stamps every day in a period" {
+                let matches = subcommand_app().get_matches_from(vec![
+                    SUBCOMMAND_NAME,
+                    "KUN1000",
+                    "--fra",
+                    "2021-03-01",
+                    "--til",
+                    "2021-03-02",
+                    "--timer",
+                    "4",
+                ]);
+                task::block_on(execute(&matches, &mut out, &api, &preferences)).unwrap();
+
+                let expected_lines = vec![
+                    "Du har allerede ført 4.0t på KUN1000 for 2021-03-01 (man)",
+                    "Førte 4.0t på KUN1000 for 2021-03-02 (tir)",
+                ];
+                let expected_entries = vec![
+                    TimeEntry {
+                        project_id: "KUN1000".to_string(),
+                        date: monday,
+                        time: Duration::hours(4),
+                    },
+                    TimeEntry {
+                        project_id: "KUN1000".to_string(),
+                        date: monday.succ(),
+                        time: Duration::hours(4),
+                    },
+                ];
+            }
+        }
+
+        context "failing" {
+            after {
+                assert!(out.is_empty());
+                assert_eq!(api.time_entries().len(), 1);
+            }
+
+            it "requires a project when none is preferred" {
+                let matches = subcommand_app()
+                    .get_matches_from(vec![SUBCOMMAND_NAME, "--dato", "2021-03-01"]);
+                let result = task::block_on(execute(&matches, &mut out, &api, &preferences));
+
+                assert!(result.unwrap_err().to_string().starts_with("Du må velge et prosjekt"));
+            }
+
+            it "refuses to stamp more than a day" {
+                let matches = subcommand_app()
+                    .get_matches_from(vec![SUBCOMMAND_NAME, "KUN1000", "--timer", "25"]);
+                let result = task::block_on(execute(&matches, &mut out, &api, &preferences));
+
+                assert_eq!(
+                    result.unwrap_err().to_string(),
+                    "Det er ikke mulig å føre 25 timer på én dag"
+                );
+            }
+
+            it "fails on unknown projects" {
+                let matches = subcommand_app()
+                    .get_matches_from(vec![SUBCOMMAND_NAME, "UKJENT", "--dato", "2021-03-01"]);
+                let result = task::block_on(execute(&matches, &mut out, &api, &preferences));
+
+                let error = result.unwrap_err();
+                assert_eq!(error.to_string(), "Fant ikke prosjektet UKJENT");
+                assert!(matches!(
+                    error.downcast_ref::<FloqError>(),
+                    Some(FloqError::Validation(_))
+                ));
+            }
+        }
+    }
+}
@@ -0,0 +1,316 @@
+//! Logging of the requests sent to Floq, turned on with `-v` or the env variable `FLOQ_LOG`.
+
+use crate::error::FloqError;
+
+use std::{
+    sync::atomic::{AtomicU8, Ordering},
+    time::Instant,
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde_json::Value;
+use surf::{
+    http::{
+        headers::{HeaderName, HeaderValues},
+        Method,
+    },
+    middleware::{Middleware, Next},
+    Client, Request, Response, StatusCode, Url,
+};
+
+pub const LOG_ENV: &str = "FLOQ_LOG";
+
+/// Values of these headers and JSON fields are never logged.
+const SECRET_HEADERS: [&str; 1] = ["authorization"];
+const SECRET_FIELDS: [&str; 2] = ["access_token", "refresh_token"];
+/// What secrets are replaced with, here and wherever else they would be shown.
+pub const HIDDEN_SECRET: &str = "<skjult>";
+
+static LEVEL: AtomicU8 = AtomicU8::new(0);
+
+/// How much is logged to stderr, each level including the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Off = 0,
+    /// The method, URL, status and duration of each request, and retries.
+    Requests = 1,
+    /// The headers and bodies of the requests.
+    RequestBodies = 2,
+    /// The bodies of the responses.
+    ResponseBodies = 3,
+}
+
+impl Level {
+    /// The level given by `-v`, repeated `occurrences` times, or by the value of `FLOQ_LOG`,
+    /// whichever is higher.
+    pub fn from_args(occurrences: u64, env: Option<&str>) -> Result<Level> {
+        let from_env = match env.map(str::trim) {
+            None | Some("") | Some("0") | Some("off") => Level::Off,
+            Some("1") | Some("info") => Level::Requests,
+            Some("2") | Some("debug") => Level::RequestBodies,
+            Some("3") | Some("trace") => Level::ResponseBodies,
+            Some(other) => {
+                return Err(anyhow!(FloqError::Validation(format!(
+                    "Ugyldig verdi for {}: {}, bruk 0-3, off, info, debug eller trace",
+                    LOG_ENV, other
+                ))))
+            }
+        };
+        let from_occurrences = match occurrences {
+            0 => Level::Off,
+            1 => Level::Requests,
+            2 => Level::RequestBodies,
+            _ => Level::ResponseBodies,
+        };
+
+        Ok(from_env.max(from_occurrences))
+    }
+}
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+fn level() -> Level {
+    match LEVEL.load(Ordering::Relaxed) {
+        0 => Level::Off,
+        1 => Level::Requests,
+        2 => Level::RequestBodies,
+        _ => Level::ResponseBodies,
+    }
+}
+
+/// Whether messages at `level` are logged.
+pub fn enabled(level: Level) -> bool {
+    level != Level::Off && self::level() >= level
+}
+
+/// Logs every request sent by the client, and its response, as allowed by the level.
+pub struct HttpLogger;
+
+#[async_trait]
+impl Middleware for HttpLogger {
+    async fn handle(
+        &self,
+        mut req: Request,
+        client: Client,
+        next: Next<'_>,
+    ) -> surf::Result<Response> {
+        let level = level();
+        if level == Level::Off {
+            return next.run(req, client).await;
+        }
+
+        let method = req.method();
+        let url = req.url().clone();
+        let mut body = vec![];
+        if level >= Level::RequestBodies {
+            body = req.take_body().into_bytes().await?;
+            req.set_body(body.clone());
+        }
+        request_log(level, &req, &body)
+            .iter()
+            .for_each(|l| eprintln!("{}", l));
+
+        let start = Instant::now();
+        let result = next.run(req, client).await;
+        let elapsed = start.elapsed().as_millis();
+
+        match result {
+            Ok(mut res) => {
+                let mut body = vec![];
+                if level >= Level::ResponseBodies {
+                    body = res.body_bytes().await?;
+                    res.set_body(body.clone());
+                }
+                response_log(level, method, &url, res.status(), elapsed, &body)
+                    .iter()
+                    .for_each(|l| eprintln!("{}", l));
+                Ok(res)
+            }
+            Err(e) => {
+                eprintln!(
+                    "<-- {} {} feilet ({} ms): {}",
+                    method,
+                    redact_url(&url),
+                    elapsed,
+                    e
+                );
+                Err(e)
+            }
+        }
+    }
+}
+
+/// The lines logged for a request at `level`, with the secrets hidden.
+fn request_log(level: Level, req: &Request, body: &[u8]) -> Vec<String> {
+    let mut lines = vec![format!("--> {} {}", req.method(), redact_url(req.url()))];
+    if level >= Level::RequestBodies {
+        for (name, values) in req.iter() {
+            lines.push(format!("    {}: {}", name, redact_header(name, values)));
+        }
+        if !body.is_empty() {
+            lines.push(format!("    {}", redact_body(body)));
+        }
+    }
+    lines
+}
+
+/// The lines logged for a response at `level`, with the secrets hidden.
+fn response_log(
+    level: Level,
+    method: Method,
+    url: &Url,
+    status: StatusCode,
+    elapsed: u128,
+    body: &[u8],
+) -> Vec<String> {
+    let mut lines = vec![format!(
+        "<-- {} {} {} ({} ms)",
+        status,
+        method,
+        redact_url(url),
+        elapsed
+    )];
+    if level >= Level::ResponseBodies && !body.is_empty() {
+        lines.push(format!("    {}", redact_body(body)));
+    }
+    lines
+}
+
+fn is_secret_field(name: &str) -> bool {
+    SECRET_FIELDS.contains(&name)
+}
+
+/// Tokens in the query are hidden like in the bodies, e.g. for requests sent with `floq api`.
+fn redact_url(url: &Url) -> String {
+    match redact_pairs(url.query().unwrap_or_default().as_bytes()) {
+        Some(query) => {
+            let mut url = url.clone();
+            url.set_query(Some(&query));
+            url.to_string()
+        }
+        None => url.to_string(),
+    }
+}
+
+/// URL encoded pairs with the secret values hidden, if there are any secrets.
+fn redact_pairs(encoded: &[u8]) -> Option<String> {
+    let pairs: Vec<(String, String)> = serde_urlencoded::from_bytes(encoded).ok()?;
+    if !pairs.iter().any(|(name, _)| is_secret_field(name)) {
+        return None;
+    }
+
+    let pairs: Vec<(String, String)> = pairs
+        .into_iter()
+        .map(|(name, value)| match is_secret_field(&name) {
+            true => (name, HIDDEN_SECRET.to_string()),
+            false => (name, value),
+        })
+        .collect();
+    serde_urlencoded::to_string(pairs).ok()
+}
+
+fn redact_header(name: &HeaderName, values: &HeaderValues) -> String {
+    if SECRET_HEADERS.contains(&name.as_str().to_lowercase().as_str()) {
+        match values.as_str().split_once(' ') {
+            Some((scheme, _)) => format!("{} {}", scheme, HIDDEN_SECRET),
+            None => HIDDEN_SECRET.to_string(),
+        }
+    } else {
+        values.as_str().to_string()
+    }
+}
+
+/// JSON and form bodies are logged with the tokens hidden, other bodies as text.
+fn redact_body(body: &[u8]) -> String {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(mut json) => {
+            redact_json(&mut json);
+            json.to_string()
+        }
+        Err(_) => redact_pairs(body).unwrap_or_else(|| String::from_utf8_lossy(body).into_owned()),
+    }
+}
+
+fn redact_json(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (key, v) in fields.iter_mut() {
+                if is_secret_field(key) {
+                    *v = Value::String(HIDDEN_SECRET.to_string());
+                } else {
+                    redact_json(v);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact_json),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+demonstrate::demonstrate! {
+    describe "redaction" {
+        use super::*;
+
+        before {
+            let levels = [
+                Level::Off,
+                Level::Requests,
+                Level::RequestBodies,
+                Level::ResponseBodies,
+            ];
+            let url =
+                Url::parse("https://api-blank.floq.no/rpc/x?access_token=SECRET-1&a=b").unwrap();
+        }
+
+        it "hides the tokens of requests at every level" {
+            let mut req = surf::post(url.clone())
+                .header("Authorization", "Bearer SECRET-2")
+                .build();
+            req.insert_header("authorization", "SECRET-3");
+            let bodies: [&[u8]; 3] = [
+                br#"{"refresh_token": "SECRET-4", "nested": [{"access_token": "SECRET-5"}]}"#,
+                b"refresh_token=SECRET-6&grant_type=refresh",
+                b"",
+            ];
+
+            for level in levels.iter() {
+                for body in bodies.iter() {
+                    let lines = request_log(*level, &req, body);
+
+                    assert!(!lines.is_empty());
+                    assert!(lines.iter().all(|l| !l.contains("SECRET")), "{:?}", lines);
+                }
+            }
+        }
+
+        it "hides the tokens of responses at every level" {
+            let body = br#"{"access_token": "SECRET-7", "refresh_token": "SECRET-8", "a": "b"}"#;
+
+            for level in levels.iter() {
+                let lines = response_log(*level, Method::Post, &url, StatusCode::Ok, 12, body);
+
+                assert!(lines.iter().all(|l| !l.contains("SECRET")), "{:?}", lines);
+            }
+        }
+
+        it "logs the response body only at the highest level" {
+            let body = br#"{"expiry_date": "2021-03-01"}"#;
+
+            for level in levels.iter() {
+                let lines = response_log(*level, Method::Post, &url, StatusCode::Ok, 12, body);
+
+                assert!(lines[0].contains("a=b"));
+                match level {
+                    Level::ResponseBodies => {
+                        assert_eq!(lines[1], r#"    {"expiry_date":"2021-03-01"}"#)
+                    }
+                    _ => assert_eq!(lines.len(), 1),
+                }
+            }
+        }
+    }
+}
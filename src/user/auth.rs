@@ -1,11 +1,15 @@
-use crate::http_client::{floq_domain, HandleInvalidToken, HandleMalformedBody};
+use super::profile::Profile;
+use crate::http_client::{surf_client, HandleInvalidToken, HandleMalformedBody};
 
-use std::io::Write;
-use std::time::Duration;
+use std::env;
+use std::io::{BufRead, Write};
+use std::time::{Duration, Instant};
 use std::{collections::HashMap, sync::mpsc};
 
 use anyhow::{anyhow, Context, Result};
 use chrono::{DateTime, FixedOffset, NaiveDateTime};
+use jwt::{Claims, Header, Token};
+use rand::{distributions::Alphanumeric, Rng};
 use rouille::{Request, Response};
 use serde::{Deserialize, Serialize};
 
@@ -16,40 +20,61 @@ pub struct AuthorizedUser {
     pub expires_at: NaiveDateTime,
 }
 
-pub async fn authorize<OUT: Write + Send>(out: &mut OUT) -> Result<AuthorizedUser> {
-    let (tx, rx) = mpsc::sync_channel::<Result<AuthorizedUser>>(0);
+pub const DEFAULT_LOGIN_TIMEOUT: Duration = Duration::from_secs(300);
 
-    let server = rouille::Server::new("0.0.0.0:0", move |request| {
-        match handle_callback(request) {
-            Ok(tokens) => {
-                tx.send(Ok(tokens)).unwrap();
+const CALLBACK_PATH: &str = "/callback/";
+
+pub async fn authorize<OUT: Write + Send>(
+    out: &mut OUT,
+    profile: &Profile,
+    timeout: Duration,
+) -> Result<AuthorizedUser> {
+    let (tx, rx) = mpsc::sync_channel::<AuthorizedUser>(0);
+    let state = random_state();
+    let expected_state = state.clone();
+
+    let server = rouille::Server::new("127.0.0.1:0", move |request| {
+        match handle_callback(request, &expected_state) {
+            None => Response::empty_404(),
+            Some(Ok(tokens)) => {
+                tx.send(tokens).unwrap();
                 Response::text("Flott, da er du logget inn i floq cli!\n\n(Bare å lukke denne fanen)")
             },
-            Err(e) => {
-                eprintln!("Error on handle callback from Floq Auth: {}", e);
-                tx.send(Err(e)).unwrap();
+            // a stray request shouldn't abort the login, the user might still complete it
+            Some(Err(e)) => {
+                eprintln!("Error on handle callback from Floq Auth: {:#}", e);
                 Response::text("An error occurred while trying to handle Auth callback, see command output for more details")
+                    .with_status_code(400)
             }
         }
     })
     .map_err(|e| anyhow!("{}", e))?;
 
-    let port = server.server_addr().port();
-
     writeln!(out)?;
     writeln!(out, "Vennligst åpne denne lenken i nettleseren din:")?;
     writeln!(
         out,
-        "{}/login/oauth?to=http://localhost:{}",
-        floq_domain(),
-        port
+        "{}",
+        login_url(
+            profile,
+            &callback_url(
+                &format!("http://{}{}", server.server_addr(), CALLBACK_PATH),
+                &state
+            )
+        )?
     )?;
     writeln!(out)?;
 
+    let deadline = Instant::now() + timeout;
     loop {
         match rx.try_iter().next() {
-            Some(Ok(tokens)) => break Ok(tokens),
-            Some(Err(e)) => break Err(e),
+            Some(tokens) => break Ok(tokens),
+            None if Instant::now() >= deadline => {
+                break Err(anyhow!(
+                    "Innloggingen ble avbrutt etter {} sekunder uten svar fra nettleseren",
+                    timeout.as_secs()
+                ))
+            }
             None => {
                 std::thread::sleep(Duration::from_millis(250));
                 server.poll();
@@ -58,15 +83,121 @@ pub async fn authorize<OUT: Write + Send>(out: &mut OUT) -> Result<AuthorizedUse
     }
 }
 
-fn handle_callback(request: &Request) -> Result<AuthorizedUser> {
-    let mut params =
-        match serde_urlencoded::from_str::<HashMap<String, String>>(request.raw_query_string()) {
-            Ok(p) => p,
-            Err(e) => {
-                return Err(anyhow!("Unable to parse callback request URL"))
-                    .with_context(|| format!("Deserialization of query params failed: {:?}", e));
-            }
-        };
+/// The page at Floq Auth that logs the user in, and redirects to `to` with the tokens in the
+/// query.
+fn login_url(profile: &Profile, to: &str) -> Result<String> {
+    let query = serde_urlencoded::to_string([("to", to)])?;
+
+    Ok(format!("{}/login/oauth?{}", profile.domain, query))
+}
+
+/// Floq Auth only redirects to `to`, so the state is part of it to come back with the tokens.
+fn callback_url(base: &str, state: &str) -> String {
+    format!("{}?state={}", base, state)
+}
+
+fn random_state() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .collect()
+}
+
+/// Login for when the browser can't reach a server on this machine, e.g. over SSH.
+/// The user is redirected to a page that won't load, and pastes its URL back to us.
+pub async fn authorize_headless<OUT: Write + Send, IN: BufRead>(
+    out: &mut OUT,
+    input: &mut IN,
+    profile: &Profile,
+) -> Result<AuthorizedUser> {
+    let state = random_state();
+
+    writeln!(out)?;
+    writeln!(
+        out,
+        "Vennligst åpne denne lenken i en nettleser, gjerne på en annen maskin:"
+    )?;
+    writeln!(
+        out,
+        "{}",
+        login_url(profile, &callback_url("http://localhost/", &state))?
+    )?;
+    writeln!(out)?;
+    writeln!(
+        out,
+        "Etter innloggingen blir du sendt til en side som ikke laster."
+    )?;
+    write!(
+        out,
+        "Kopier hele adressen til den siden og lim den inn her: "
+    )?;
+    out.flush()?;
+
+    let mut pasted = String::new();
+    input
+        .read_line(&mut pasted)
+        .with_context(|| "Klarte ikke å lese adressen som ble limt inn")?;
+    writeln!(out)?;
+
+    read_pasted_callback(&pasted, &state)
+}
+
+/// The tokens in a pasted callback URL, which must have the state embedded in the `to` given to
+/// Floq Auth.
+fn read_pasted_callback(pasted: &str, expected_state: &str) -> Result<AuthorizedUser> {
+    let query = callback_query(pasted);
+    check_state(query, expected_state)?;
+    handle_callback_query(query)
+}
+
+/// The query string of a pasted callback URL, the input is assumed to be the query string itself if
+/// it's not a URL.
+fn callback_query(pasted: &str) -> &str {
+    let pasted = pasted.trim();
+    let query = pasted.split_once('?').map(|(_, q)| q).unwrap_or(pasted);
+
+    query.split('#').next().unwrap_or_default()
+}
+
+/// Requests to anything but the callback path, like the browser asking for a favicon, are ignored.
+fn handle_callback(request: &Request, expected_state: &str) -> Option<Result<AuthorizedUser>> {
+    if request.url() != CALLBACK_PATH {
+        return None;
+    }
+
+    let query = request.raw_query_string();
+    Some(check_state(query, expected_state).and_then(|_| handle_callback_query(query)))
+}
+
+/// The state is part of the callback URL given to Floq Auth, so a callback with another state was
+/// not started by this login.
+fn check_state(query: &str, expected_state: &str) -> Result<()> {
+    let state = callback_params(query)
+        .ok()
+        .and_then(|mut params| params.remove("state"));
+
+    match state {
+        Some(state) if state == expected_state => Ok(()),
+        _ => Err(anyhow!(
+            "Callback from Floq Auth has an unknown state, the login was not started by this command"
+        )),
+    }
+}
+
+/// The params of a callback query. The tokens may be added to the callback URL with another `?`
+/// after the state, which is never part of a value as it would be encoded.
+fn callback_params(query: &str) -> Result<HashMap<String, String>, serde_urlencoded::de::Error> {
+    serde_urlencoded::from_str(&query.replace('?', "&"))
+}
+
+fn handle_callback_query(query: &str) -> Result<AuthorizedUser> {
+    let mut params = match callback_params(query) {
+        Ok(p) => p,
+        Err(e) => {
+            return Err(anyhow!("Unable to parse callback request URL"))
+                .with_context(|| format!("Deserialization of query params failed: {:?}", e));
+        }
+    };
 
     let access_token = match params.remove("access_token") {
         Some(at) => at,
@@ -111,7 +242,7 @@ fn handle_callback(request: &Request) -> Result<AuthorizedUser> {
 }
 
 #[derive(Serialize)]
-struct RefreshAccessTokenRequest<'a> {
+struct RefreshTokenRequest<'a> {
     refresh_token: &'a str,
 }
 
@@ -119,28 +250,38 @@ struct RefreshAccessTokenRequest<'a> {
 struct RefreshAccessTokenResponse {
     access_token: String,
     expiry_date: DateTime<FixedOffset>,
+    /// Only present if Floq rotates the refresh token.
+    #[serde(default)]
+    refresh_token: Option<String>,
 }
 
 impl RefreshAccessTokenResponse {
     fn into_authorized_user(self, refresh_token: &str) -> AuthorizedUser {
         AuthorizedUser {
             access_token: self.access_token,
-            refresh_token: refresh_token.to_string(),
+            refresh_token: self
+                .refresh_token
+                .unwrap_or_else(|| refresh_token.to_string()),
             expires_at: self.expiry_date.naive_utc(),
         }
     }
 }
 
-pub async fn refresh_access_token(refresh_token: &str) -> Result<AuthorizedUser> {
-    let request_body = RefreshAccessTokenRequest { refresh_token };
+pub async fn refresh_access_token(
+    profile: &Profile,
+    refresh_token: &str,
+) -> Result<AuthorizedUser> {
+    let request_body = RefreshTokenRequest { refresh_token };
     let request_body = serde_json::to_string(&request_body)?;
-    let request = surf::post(format!("{}/login/oauth/refresh", floq_domain()))
+    let request = surf_client(&profile.domain, profile)?
+        .post("login/oauth/refresh")
         .header("Content-Type", "application/json")
         .body(request_body);
 
     let mut response = request.send()
         .await
         .handle_floq_response()
+        .await
         .with_context(|| "Noe gikk galt under oppdatering av innloggingsinformasjonen, vennligst logg inn på nytt")?;
 
     let tokens: RefreshAccessTokenResponse = response
@@ -151,3 +292,186 @@ pub async fn refresh_access_token(refresh_token: &str) -> Result<AuthorizedUser>
 
     Ok(tokens.into_authorized_user(refresh_token))
 }
+
+/// Makes Floq invalidate the refresh token, so that it can't be used to get new access tokens.
+pub async fn revoke_refresh_token(
+    profile: &Profile,
+    access_token: &str,
+    refresh_token: &str,
+) -> Result<()> {
+    let request_body = RefreshTokenRequest { refresh_token };
+    let request_body = serde_json::to_string(&request_body)?;
+    let request = surf_client(&profile.domain, profile)?
+        .post("login/oauth/revoke")
+        .header("Authorization", format!("Bearer {}", access_token))
+        .header("Content-Type", "application/json")
+        .body(request_body);
+
+    request
+        .send()
+        .await
+        .handle_floq_response()
+        .await
+        .map(|_| ())
+        .with_context(|| format!("Klarte ikke å logge ut av {} hos Floq", profile.domain))
+}
+
+/// Reads the claims of an access token without verifying its signature.
+pub fn decode_access_token(access_token: &str) -> Result<Claims> {
+    let token: Token<Header, Claims, _> = Token::parse_unverified(access_token)
+        .map_err(|e| anyhow!("Klarte ikke å lese access-tokenet: {}", e))?;
+
+    let (_header, claims) = token.into();
+
+    Ok(claims)
+}
+
+pub const ACCESS_TOKEN_ENV: &str = "FLOQ_ACCESS_TOKEN";
+pub const REFRESH_TOKEN_ENV: &str = "FLOQ_REFRESH_TOKEN";
+pub const TOKEN_FILE_ENV: &str = "FLOQ_TOKEN_FILE";
+
+pub struct ServiceTokens {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+}
+
+/// Tokens given to floq by the environment, for automation where there is no one to log in.
+/// `FLOQ_ACCESS_TOKEN` and `FLOQ_REFRESH_TOKEN` take precedence over the file at `FLOQ_TOKEN_FILE`,
+/// which has the access token on the first line and optionally the refresh token on the second.
+pub async fn service_tokens_from_env() -> Result<Option<ServiceTokens>> {
+    let non_empty = |v: String| Some(v.trim().to_string()).filter(|v| !v.is_empty());
+
+    if let Some(access_token) = env::var(ACCESS_TOKEN_ENV).ok().and_then(non_empty) {
+        return Ok(Some(ServiceTokens {
+            access_token,
+            refresh_token: env::var(REFRESH_TOKEN_ENV).ok().and_then(non_empty),
+        }));
+    }
+
+    let path = match env::var(TOKEN_FILE_ENV).ok().and_then(non_empty) {
+        Some(p) => p,
+        None => return Ok(None),
+    };
+    let content = async_std::fs::read_to_string(&path)
+        .await
+        .with_context(|| format!("Klarte ikke å lese tokenfilen {}", path))?;
+    let mut lines = content.lines().map(|l| l.trim().to_string());
+
+    match lines.next().and_then(non_empty) {
+        Some(access_token) => Ok(Some(ServiceTokens {
+            access_token,
+            refresh_token: lines.next().and_then(non_empty),
+        })),
+        None => Err(anyhow!(
+            "Tokenfilen {} inneholder ikke noe access-token",
+            path
+        )),
+    }
+}
+
+#[cfg(test)]
+demonstrate::demonstrate! {
+    describe "handle_callback" {
+        use super::*;
+
+        before {
+            let tokens = "access_token=access&refresh_token=refresh&expiry_date=2021-03-01T12%3A00%3A00%2B01%3A00";
+            let callback = |url: &str| Request::fake_http("GET", url, vec![], vec![]);
+        }
+
+        it "returns the tokens when the state matches" {
+            let request = callback(&format!("/callback/?state=abc&{}", tokens));
+
+            let user = handle_callback(&request, "abc").unwrap().unwrap();
+
+            assert_eq!(user.access_token, "access");
+            assert_eq!(user.refresh_token, "refresh");
+            assert_eq!(user.expires_at.to_string(), "2021-03-01 11:00:00");
+        }
+
+        it "accepts tokens added after the state with another question mark" {
+            let request = callback(&format!("/callback/?state=abc?{}", tokens));
+
+            let user = handle_callback(&request, "abc").unwrap().unwrap();
+
+            assert_eq!(user.access_token, "access");
+        }
+
+        it "rejects another or a missing state" {
+            for url in &[
+                format!("/callback/?state=xyz&{}", tokens),
+                format!("/callback/?{}", tokens),
+            ] {
+                assert!(handle_callback(&callback(url), "abc").unwrap().is_err());
+            }
+        }
+
+        it "ignores other paths" {
+            for path in &["/favicon.ico", "/callback/abc", "/"] {
+                let request = callback(&format!("{}?state=abc&{}", path, tokens));
+
+                assert!(handle_callback(&request, "abc").is_none());
+            }
+        }
+
+        it "rejects a callback missing a token" {
+            let tokens = tokens.replace("refresh_token=refresh&", "");
+            let request = callback(&format!("/callback/?state=abc&{}", tokens));
+
+            assert!(handle_callback(&request, "abc").unwrap().is_err());
+        }
+    }
+
+    describe "read_pasted_callback" {
+        use super::*;
+
+        before {
+            let tokens = "access_token=access&refresh_token=refresh&expiry_date=2021-03-01T12%3A00%3A00%2B01%3A00";
+            let to = callback_url("http://localhost/", "abc");
+        }
+
+        it "reads the tokens added to the callback URL" {
+            for pasted in [format!("{}?{}", to, tokens), format!("{}&{}", to, tokens)] {
+                let user = read_pasted_callback(&pasted, "abc").unwrap();
+
+                assert_eq!(user.access_token, "access");
+            }
+        }
+
+        it "rejects a URL from another login" {
+            let pasted = format!("{}?{}", to, tokens);
+
+            assert!(read_pasted_callback(&pasted, "xyz").is_err());
+            assert!(read_pasted_callback(&format!("http://localhost/?{}", tokens), "abc").is_err());
+        }
+    }
+
+    describe "callback_query" {
+        use super::*;
+
+        it "finds the query in a full URL" {
+            assert_eq!(
+                callback_query("http://localhost/?access_token=a&state=abc"),
+                "access_token=a&state=abc"
+            );
+        }
+
+        it "accepts a bare query string" {
+            assert_eq!(callback_query("access_token=a&state=abc"), "access_token=a&state=abc");
+        }
+
+        it "leaves out the fragment" {
+            assert_eq!(
+                callback_query("http://localhost/?access_token=a&state=abc#/"),
+                "access_token=a&state=abc"
+            );
+        }
+
+        it "ignores surrounding whitespace" {
+            assert_eq!(
+                callback_query("  http://localhost/?access_token=a&state=abc \r\n"),
+                "access_token=a&state=abc"
+            );
+        }
+    }
+}
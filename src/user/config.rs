@@ -1,13 +1,134 @@
-use std::{env, io::ErrorKind};
+use super::{crypto, profile, profile::Profile};
+use crate::{error::FloqError, print::TableStyle};
 
-use async_std::fs;
+use std::{
+    collections::BTreeMap,
+    env,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    process,
+};
 
-use anyhow::{Context, Result};
-use chrono::NaiveDateTime;
+use async_std::{fs, io::prelude::WriteExt, task};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{Duration, NaiveDateTime};
+use fs2::FileExt;
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+/// Version 1 is the layout from before profiles, with the credentials at the top level.
+const CONFIG_VERSION: i64 = 2;
+
+/// Unknown fields are rejected, so that a layout floq doesn't recognize is never read as an empty
+/// config, which would log the user out.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct UserConfig {
+    #[serde(default)]
+    pub version: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active_profile: Option<String>,
+    /// Credentials are encrypted with a passphrase before they are written to disk.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub encrypt_credentials: bool,
+    #[serde(
+        rename = "preferanser",
+        default,
+        skip_serializing_if = "Preferences::is_default"
+    )]
+    pub preferences: Preferences,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, ProfileConfig>,
+}
+
+/// Defaults used by the subcommands when the corresponding flags are omitted.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Preferences {
+    /// Hours to stamp when `--timer` is omitted.
+    #[serde(rename = "timer")]
+    pub default_hours: f32,
+    /// Project to stamp when the project is omitted.
+    #[serde(rename = "prosjekt", skip_serializing_if = "Option::is_none")]
+    pub default_project: Option<String>,
+    /// How many weeks back to look for the projects you have stamped hours on.
+    #[serde(rename = "historikk-uker")]
+    pub history_weeks: u32,
+    #[serde(rename = "tabellstil")]
+    pub table_style: TableStyle,
+    #[serde(rename = "helg")]
+    pub weekend: WeekendDisplay,
+    /// How many times a request that failed because of the network or Floq is sent again.
+    #[serde(rename = "gjentakelser")]
+    pub retries: u32,
+    /// Milliseconds to wait before the first retry, doubled for each of the next.
+    #[serde(rename = "ventetid-ms")]
+    pub retry_delay_ms: u64,
+}
+
+/// When to show saturday and sunday in the weekly history.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum WeekendDisplay {
+    /// Only if hours are stamped in the weekend.
+    #[serde(rename = "auto")]
+    Auto,
+    #[serde(rename = "alltid")]
+    Always,
+    #[serde(rename = "aldri")]
+    Never,
+}
+
+impl Default for Preferences {
+    fn default() -> Self {
+        Preferences {
+            default_hours: 7.5,
+            default_project: None,
+            history_weeks: 2,
+            table_style: TableStyle::default(),
+            weekend: WeekendDisplay::Auto,
+            retries: 2,
+            retry_delay_ms: 250,
+        }
+    }
+}
+
+impl Preferences {
+    fn is_default(&self) -> bool {
+        *self == Preferences::default()
+    }
+
+    /// Rejects values that are valid TOML, but which no subcommand could use.
+    fn validate(&self) -> Result<()> {
+        let invalid = |message: &str| Err(anyhow!(FloqError::Validation(message.to_string())));
+        if !(self.default_hours > 0.0 && self.default_hours <= 24.0) {
+            return invalid("Preferansen timer må være mer enn 0 og høyst 24, f.eks. 7.5");
+        }
+        if self.history_weeks == 0 {
+            return invalid("Preferansen historikk-uker må være minst 1");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileConfig {
+    pub domain: String,
+    pub api_domain: String,
+    /// A PEM file with CA certificates trusted in addition to the system's when connecting to
+    /// the profile, e.g. on a network inspecting TLS. Kept before the credentials, as TOML needs
+    /// the values of a table before the tables in it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_certificates: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credentials: Option<Credentials>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encrypted_credentials: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Credentials {
     pub employee_id: u16,
     pub email: String,
     pub name: String,
@@ -16,64 +137,433 @@ pub struct UserConfig {
     pub refresh_token: String,
 }
 
-fn home_path() -> String {
-    env::var("HOME")
-        .or_else(|_| env::var("HOMEPATH"))
-        .expect("Did not find env var 'HOME' or 'HOMEPATH'")
+impl Default for UserConfig {
+    fn default() -> Self {
+        UserConfig {
+            version: CONFIG_VERSION,
+            active_profile: None,
+            encrypt_credentials: false,
+            preferences: Preferences::default(),
+            profiles: BTreeMap::new(),
+        }
+    }
 }
 
-fn folder_path() -> String {
-    home_path() + "/.floq"
+impl UserConfig {
+    pub fn credentials(&self, profile: &Profile) -> Option<&Credentials> {
+        self.profiles
+            .get(&profile.name)
+            .and_then(|pc| pc.credentials.as_ref())
+    }
+
+    /// The stored config for the given profile, created from the profile's domains if missing.
+    pub fn profile_mut(&mut self, profile: &Profile) -> &mut ProfileConfig {
+        self.profiles
+            .entry(profile.name.clone())
+            .or_insert_with(|| ProfileConfig {
+                domain: profile.domain.clone(),
+                api_domain: profile.api_domain.clone(),
+                ca_certificates: None,
+                credentials: None,
+                encrypted_credentials: None,
+            })
+    }
 }
 
-fn file_path() -> String {
-    home_path() + "/.floq/user-config.toml"
+impl Credentials {
+    pub fn needs_refresh(&self, now: NaiveDateTime) -> bool {
+        self.access_token_expires < now - Duration::minutes(1)
+    }
 }
 
-pub async fn load_config() -> Result<Option<UserConfig>> {
-    let r = fs::read_to_string(file_path())
-        .await
-        .and_then(|s| toml::from_str::<UserConfig>(s.as_str()).map_err(|e| e.into()))
-        .map_err(|e| match e.kind() {
-            ErrorKind::NotFound => Ok(None),
-            _ => Err(e),
-        });
+pub const CONFIG_DIR_ENV: &str = "FLOQ_CONFIG_DIR";
+
+const CONFIG_FILE_NAME: &str = "user-config.toml";
+const LOCK_FILE_NAME: &str = "user-config.lock";
+
+/// The first of `HOME`, `USERPROFILE` and `HOMEPATH` that is set, where an empty value counts as
+/// unset, as it would lead to paths relative to the working dir.
+fn home_dir() -> Result<PathBuf> {
+    ["HOME", "USERPROFILE", "HOMEPATH"]
+        .iter()
+        .find_map(|name| env::var_os(name).filter(|h| !h.is_empty()))
+        .map(PathBuf::from)
+        .ok_or_else(|| {
+            anyhow!(
+                "Fant ikke hjemmemappen din, sett HOME eller {} til mappen konfigurasjonen skal ligge i",
+                CONFIG_DIR_ENV
+            )
+        })
+}
 
-    match r {
-        Ok(uc) => Ok(Some(uc)),
-        Err(e) => e.map_err(|e| e.into()),
+/// An env variable with an absolute path, as relative paths are to be ignored according to the
+/// XDG base directory specification.
+fn absolute_env_path(name: &str) -> Option<PathBuf> {
+    env::var_os(name)
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+}
+
+/// `$FLOQ_CONFIG_DIR`, or else `$XDG_CONFIG_HOME/floq` where `XDG_CONFIG_HOME` defaults to
+/// `~/.config`.
+pub fn config_dir() -> Result<PathBuf> {
+    if let Some(dir) = env::var_os(CONFIG_DIR_ENV).filter(|d| !d.is_empty()) {
+        return Ok(PathBuf::from(dir));
     }
+
+    let config_home = match absolute_env_path("XDG_CONFIG_HOME") {
+        Some(p) => p,
+        None => home_dir()?.join(".config"),
+    };
+
+    Ok(config_home.join("floq"))
 }
 
-pub async fn update_config(config: &UserConfig) -> Result<()> {
-    let file_content = toml::to_vec(config).with_context(|| {
-        "Klarte ikke å bygge inneholdet i konfigigurasjonsfilen, vennligst logg inn på nytt"
-    })?;
+/// `$XDG_CACHE_HOME/floq` where `XDG_CACHE_HOME` defaults to `~/.cache`.
+pub fn cache_dir() -> Result<PathBuf> {
+    let cache_home = match absolute_env_path("XDG_CACHE_HOME") {
+        Some(p) => p,
+        None => home_dir()?.join(".cache"),
+    };
+
+    Ok(cache_home.join("floq"))
+}
+
+/// Where floq stored its config before it followed the XDG base directory specification.
+fn legacy_config_dir() -> Result<PathBuf> {
+    Ok(home_dir()?.join(".floq"))
+}
 
-    match fs::create_dir(folder_path()).await {
+pub fn file_path() -> Result<PathBuf> {
+    Ok(config_dir()?.join(CONFIG_FILE_NAME))
+}
+
+/// Whether the config is in `~/.floq` and there's none in the config dir yet.
+fn in_legacy_dir() -> Result<bool> {
+    if env::var_os(CONFIG_DIR_ENV).is_some() {
+        return Ok(false);
+    }
+
+    Ok(!file_path()?.exists() && legacy_config_dir()?.join(CONFIG_FILE_NAME).exists())
+}
+
+/// Moves the config from `~/.floq` if there's none in the config dir yet.
+async fn migrate_legacy_dir() -> Result<()> {
+    if !in_legacy_dir()? {
+        return Ok(());
+    }
+
+    let legacy_dir = legacy_config_dir()?;
+    let legacy_file = legacy_dir.join(CONFIG_FILE_NAME);
+    let file = file_path()?;
+
+    create_dir(&config_dir()?).await?;
+    let moved = match fs::rename(&legacy_file, &file).await {
         Ok(_) => Ok(()),
-        Err(e) => match e.kind() {
-            ErrorKind::AlreadyExists => Ok(()),
-            _ => Err(e),
+        // another floq command moved it first
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        // e.g. when the dirs are on different file systems
+        Err(_) => match fs::copy(&legacy_file, &file).await {
+            Ok(_) => fs::remove_file(&legacy_file).await,
+            Err(e) => Err(e),
         },
+    };
+    moved.with_context(|| {
+        format!(
+            "Klarte ikke å flytte konfigurasjonsfilen fra {} til {}",
+            legacy_file.display(),
+            file.display()
+        )
+    })?;
+
+    // the rest of the old dir is only removed if it's left empty
+    let _ = fs::remove_file(legacy_dir.join(LOCK_FILE_NAME)).await;
+    let _ = fs::remove_dir(&legacy_dir).await;
+
+    Ok(())
+}
+
+pub async fn load_config() -> Result<UserConfig> {
+    let mut config = read_config().await?;
+
+    for pc in config.profiles.values_mut() {
+        if let Some(encrypted) = pc.encrypted_credentials.take() {
+            let decrypted = crypto::decrypt(&crypto::passphrase()?, &encrypted)?;
+            pc.credentials = Some(toml::from_slice(&decrypted).with_context(|| {
+                "Klarte ikke å lese den dekrypterte innloggingen, vennligst logg inn på nytt"
+            })?);
+        }
+    }
+
+    Ok(config)
+}
+
+/// Reads the config without decrypting the credentials.
+pub async fn read_config() -> Result<UserConfig> {
+    if !in_legacy_dir()? {
+        let (config, migrated) = read_file().await?;
+        if !migrated {
+            return Ok(config);
+        }
     }
-    .with_context(|| format!("Klarte ikke å opprette mappen {}", folder_path()))?;
 
-    fs::write(file_path(), file_content).await.with_context(|| {
+    // the config is migrated when it's locked, so that floq commands running at the same time
+    // don't move or rewrite it at once
+    let _lock = lock_config().await?;
+    Ok(read_file().await?.0)
+}
+
+/// Moves and rewrites the config if it's in an older location or layout. Only called with the
+/// config locked.
+async fn migrate_config() -> Result<()> {
+    migrate_legacy_dir().await?;
+
+    let (config, migrated) = read_file().await?;
+    if migrated {
+        update_config(&config).await?;
+    }
+    Ok(())
+}
+
+/// Reads the config file in the current layout, and whether it had to be migrated from an older one.
+async fn read_file() -> Result<(UserConfig, bool)> {
+    let file = file_path()?;
+    let content = match fs::read_to_string(&file).await {
+        Ok(c) => c,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok((UserConfig::default(), false)),
+        Err(e) => {
+            return Err(e).with_context(|| {
+                format!("Klarte ikke å lese konfigurasjonsfilen {}", file.display())
+            })
+        }
+    };
+    let content: toml::Value = toml::from_str(&content)
+        .with_context(|| format!("Konfigurasjonsfilen {} er ikke gyldig TOML", file.display()))?;
+
+    migrate(content, &file)
+}
+
+/// Reads config given as TOML, e.g. after it has been edited by the user.
+pub fn from_value(content: toml::Value) -> Result<UserConfig> {
+    let (config, _) = migrate(content, &file_path()?)?;
+    config.preferences.validate()?;
+    Ok(config)
+}
+
+/// Reads the config in the current layout, and whether it had to be migrated from an older one.
+fn migrate(content: toml::Value, file: &Path) -> Result<(UserConfig, bool)> {
+    let version = match content.get("version").and_then(|v| v.as_integer()) {
+        Some(v) => v,
+        None if content.get("employee_id").is_some() => 1,
+        None => {
+            return Err(anyhow!(
+                "Konfigurasjonsfilen {} har et ukjent format",
+                file.display()
+            ))
+        }
+    };
+
+    match version {
+        1 => {
+            // the credentials belonged to the environment floq was compiled for
+            let credentials: Credentials = content
+                .try_into()
+                .with_context(|| "Klarte ikke å lese den gamle konfigurasjonsfilen")?;
+            let mut config = UserConfig::default();
+            let profile = profile::find(&config, profile::DEFAULT_PROFILE)
+                .ok_or_else(|| anyhow!("Fant ikke standardprofilen {}", profile::DEFAULT_PROFILE))?;
+            config.profile_mut(&profile).credentials = Some(credentials);
+
+            Ok((config, true))
+        }
+        v if v <= CONFIG_VERSION => {
+            let mut config: UserConfig = content.try_into().with_context(|| {
+                format!("Konfigurasjonsfilen {} har et ugyldig innhold", file.display())
+            })?;
+            let migrated = config.version != CONFIG_VERSION;
+            config.version = CONFIG_VERSION;
+
+            Ok((config, migrated))
+        }
+        v => Err(anyhow!(
+            "Konfigurasjonsfilen {} har versjon {}, som er nyere enn denne versjonen av floq støtter",
+            file.display(),
+            v
+        )),
+    }
+}
+
+/// Held while the config is read, changed and written back, e.g. when the stored tokens are
+/// refreshed, so that floq commands running at the same time don't overwrite each other's changes.
+/// The config is migrated to the current location and layout once it's locked. The lock is
+/// released when this is dropped.
+pub struct ConfigLock(std::fs::File);
+
+pub async fn lock_config() -> Result<ConfigLock> {
+    // the lock file is kept with the cache, as it's not part of the config
+    let dir = cache_dir()?;
+    create_dir(&dir).await?;
+
+    let path = dir.join(LOCK_FILE_NAME);
+    let error_context = format!("Klarte ikke å låse {}", path.display());
+    let lock = task::spawn_blocking(move || {
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        file.lock_exclusive()?;
+
+        Ok::<_, std::io::Error>(ConfigLock(file))
+    })
+    .await
+    .context(error_context)?;
+
+    migrate_config().await?;
+    Ok(lock)
+}
+
+impl Drop for ConfigLock {
+    fn drop(&mut self) {
+        let _ = self.0.unlock();
+    }
+}
+
+/// A copy of the config where the credentials are replaced by their encrypted form.
+fn encrypted(config: &UserConfig) -> Result<UserConfig> {
+    let mut config = config.clone();
+
+    for pc in config.profiles.values_mut() {
+        if let Some(credentials) = pc.credentials.take() {
+            let plaintext = toml::to_vec(&credentials)?;
+            pc.encrypted_credentials = Some(crypto::encrypt(&crypto::passphrase()?, &plaintext)?);
+        }
+    }
+
+    Ok(config)
+}
+
+pub async fn update_config(config: &UserConfig) -> Result<()> {
+    let file_content = if config.encrypt_credentials {
+        toml::to_vec(&encrypted(config)?)
+    } else {
+        toml::to_vec(config)
+    }
+    .with_context(|| {
+        "Klarte ikke å bygge inneholdet i konfigigurasjonsfilen, vennligst logg inn på nytt"
+    })?;
+
+    create_dir(&config_dir()?).await?;
+
+    // written to a temporary file first so that the config is never left half written
+    let file = file_path()?;
+    let temp_path = file.with_extension(format!("toml.{}.tmp", process::id()));
+    let written = match write_private(&temp_path, &file_content).await {
+        Ok(_) => fs::rename(&temp_path, &file).await,
+        Err(e) => Err(e),
+    };
+
+    written.with_context(|| {
         format!(
             "Klarte ikke å skrive til konfirgurasjonsfilen {}",
-            file_path()
+            file.display()
         )
     })
 }
 
-pub async fn delete_config() -> Result<()> {
-    match fs::remove_file(file_path()).await {
-        Ok(_) => Ok(()),
-        Err(e) => match e.kind() {
-            ErrorKind::NotFound => Ok(()),
-            _ => Err(e),
-        },
+async fn create_dir(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)
+        .await
+        .with_context(|| format!("Klarte ikke å opprette mappen {}", dir.display()))
+}
+
+/// Writes the file so that it's only readable by the current user.
+pub async fn write_private(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use async_std::os::unix::fs::OpenOptionsExt;
+        use std::os::unix::fs::PermissionsExt;
+
+        options.mode(0o600);
+        // the mode is only used when the file is created
+        if fs::metadata(path).await.is_ok() {
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600)).await?;
+        }
+    }
+
+    let mut file = options.open(path).await?;
+    file.write_all(content).await?;
+    file.flush().await
+}
+
+#[cfg(test)]
+demonstrate::demonstrate! {
+    describe "migrate" {
+        use super::*;
+
+        before {
+            let file = PathBuf::from("user-config.toml");
+        }
+
+        it "moves the credentials of version 1 into the default profile" {
+            let content: toml::Value = toml::from_str(
+                r#"
+                employee_id = 1
+                email = "ola@blank.no"
+                name = "Ola Nordmann"
+                access_token = "access"
+                access_token_expires = "2021-03-01T12:00:00"
+                refresh_token = "refresh"
+                "#,
+            )
+            .unwrap();
+
+            let (config, migrated) = migrate(content, &file).unwrap();
+            let credentials = config.profiles[profile::DEFAULT_PROFILE]
+                .credentials
+                .as_ref()
+                .unwrap();
+
+            assert!(migrated);
+            assert_eq!(config.version, CONFIG_VERSION);
+            assert_eq!(credentials.employee_id, 1);
+            assert_eq!(credentials.refresh_token, "refresh");
+        }
+
+        it "rejects an unknown layout" {
+            let content: toml::Value = toml::from_str(
+                r#"
+                email = "ola@blank.no"
+                access_token = "access"
+                "#,
+            )
+            .unwrap();
+
+            assert!(migrate(content, &file).is_err());
+        }
+    }
+
+    describe "validate" {
+        use super::*;
+
+        it "rejects hours and weeks no subcommand could use" {
+            for hours in [-3.0, 0.0, 24.5, f32::NAN] {
+                let preferences = Preferences {
+                    default_hours: hours,
+                    ..Preferences::default()
+                };
+                let e = preferences.validate().unwrap_err();
+                assert!(matches!(e.downcast_ref(), Some(FloqError::Validation(_))));
+            }
+            let preferences = Preferences {
+                history_weeks: 0,
+                ..Preferences::default()
+            };
+
+            assert!(preferences.validate().is_err());
+            assert!(Preferences::default().validate().is_ok());
+        }
     }
-    .with_context(|| format!("Klarte ikke å slette konfirgurasjonsfilen {}", file_path()))
 }
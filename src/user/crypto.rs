@@ -0,0 +1,127 @@
+use crate::error::FloqError;
+
+use std::{
+    env,
+    io::{self, IsTerminal},
+    sync::Mutex,
+};
+
+use anyhow::{anyhow, Context, Result};
+use argon2::Argon2;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use rand::Rng;
+
+pub const PASSPHRASE_ENV: &str = "FLOQ_PASSPHRASE";
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// The passphrase is asked for at most once per run.
+static PASSPHRASE: Mutex<Option<String>> = Mutex::new(None);
+
+/// The passphrase from `FLOQ_PASSPHRASE`, or asked for in the terminal. Without a terminal to ask
+/// in, a missing passphrase is a [`FloqError::Passphrase`] error.
+pub fn passphrase() -> Result<String> {
+    let mut cached = PASSPHRASE.lock().unwrap();
+    if let Some(p) = cached.as_ref() {
+        return Ok(p.clone());
+    }
+
+    let passphrase = match env::var(PASSPHRASE_ENV) {
+        Ok(p) if !p.is_empty() => p,
+        _ if !io::stdin().is_terminal() => return Err(anyhow!(FloqError::Passphrase)),
+        _ => rpassword::prompt_password("Passord for innloggingen i floq: ")
+            .map_err(|e| anyhow!(e).context(FloqError::Passphrase))?,
+    };
+    *cached = Some(passphrase.clone());
+
+    Ok(passphrase)
+}
+
+/// Use `passphrase` for the rest of the run, e.g. after choosing a new one.
+pub fn set_passphrase(passphrase: String) {
+    *PASSPHRASE.lock().unwrap() = Some(passphrase);
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Key> {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow!("Klarte ikke å lage en nøkkel fra passordet: {}", e))?;
+
+    Ok(key)
+}
+
+/// Encrypts `plaintext` with a key derived from `passphrase`, and returns the salt, nonce and
+/// ciphertext together as base64.
+pub fn encrypt(passphrase: &str, plaintext: &[u8]) -> Result<String> {
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill(&mut salt);
+    rand::thread_rng().fill(&mut nonce);
+
+    let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, &salt)?);
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), plaintext)
+        .map_err(|_| anyhow!("Klarte ikke å kryptere innloggingen"))?;
+
+    Ok(BASE64.encode([&salt[..], &nonce[..], &ciphertext[..]].concat()))
+}
+
+pub fn decrypt(passphrase: &str, encrypted: &str) -> Result<Vec<u8>> {
+    let bytes = BASE64
+        .decode(encrypted)
+        .with_context(|| "Den krypterte innloggingen er ikke gyldig base64")?;
+    if bytes.len() < SALT_LEN + NONCE_LEN {
+        return Err(anyhow!("Den krypterte innloggingen er for kort"));
+    }
+    let (salt, rest) = bytes.split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+    let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, salt)?);
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| {
+            anyhow!(FloqError::Passphrase).context(
+                "Klarte ikke å dekryptere innloggingen, passordet er feil eller den er endret",
+            )
+        })
+}
+
+#[cfg(test)]
+demonstrate::demonstrate! {
+    describe "encryption" {
+        use super::*;
+
+        before {
+            let encrypted = encrypt("hemmelig", b"refresh_token = \"abc\"").unwrap();
+        }
+
+        it "decrypts what it encrypted" {
+            assert_eq!(
+                decrypt("hemmelig", &encrypted).unwrap(),
+                b"refresh_token = \"abc\""
+            );
+        }
+
+        it "rejects another passphrase" {
+            let error = decrypt("feil", &encrypted).unwrap_err();
+
+            assert_eq!(error.downcast_ref(), Some(&FloqError::Passphrase));
+        }
+
+        it "rejects a changed ciphertext" {
+            let mut bytes = BASE64.decode(&encrypted).unwrap();
+            let last = bytes.len() - 1;
+            bytes[last] ^= 1;
+
+            let error = decrypt("hemmelig", &BASE64.encode(bytes)).unwrap_err();
+
+            assert_eq!(error.downcast_ref(), Some(&FloqError::Passphrase));
+        }
+    }
+}
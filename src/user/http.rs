@@ -1,8 +1,9 @@
-use super::Employee;
-use crate::http_client::{floq_api_domain, HandleInvalidToken, HandleMalformedBody};
+use super::{profile::Profile, Employee};
+use crate::http_client::{surf_client, HandleInvalidToken, HandleMalformedBody, HttpClient};
 
 use anyhow::{Context, Result};
 use serde::Deserialize;
+use surf::Response;
 
 #[derive(Deserialize)]
 struct EmployeeResponse {
@@ -23,15 +24,33 @@ impl EmployeeResponse {
     }
 }
 
-pub async fn get_logged_in_employee(access_token: &str) -> Result<Employee> {
-    let mut response = surf::post(format!("{}/rpc/who_am_i", floq_api_domain()))
+pub async fn get_logged_in_employee(profile: &Profile, access_token: &str) -> Result<Employee> {
+    let response = surf_client(&profile.api_domain, profile)?
+        .post("rpc/who_am_i")
         .header("Accept", "application/json")
         .header("Authorization", format!("Bearer {}", access_token))
         .send()
         .await
         .handle_floq_response()
+        .await
         .with_context(|| "Noe gikk galt under henting av informasjon om deg")?;
 
+    read_employee(response).await
+}
+
+impl HttpClient {
+    /// The employee the client is logged in as.
+    pub async fn get_logged_in_employee(&self) -> Result<Employee> {
+        let response = self
+            .send_read(|| self.post("rpc/who_am_i"))
+            .await
+            .with_context(|| "Noe gikk galt under henting av informasjon om deg")?;
+
+        read_employee(response).await
+    }
+}
+
+async fn read_employee(mut response: Response) -> Result<Employee> {
     let response: [EmployeeResponse; 1] = response
         .body_json()
         .await
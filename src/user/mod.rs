@@ -1,15 +1,31 @@
-use crate::cmd::Subcommand;
+use crate::{
+    cmd::{self, Subcommand},
+    error::FloqError,
+    print::TableMaker,
+};
 
-use std::io::Write;
+use std::{
+    env,
+    fmt::Display,
+    io::{self, Write},
+    path::PathBuf,
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
-use chrono::{Duration, Utc};
-use clap::{App, AppSettings, ArgMatches};
+use chrono::{Duration, NaiveDateTime, Utc};
+use clap::{App, AppSettings, Arg, ArgMatches};
 
-mod auth;
-mod config;
+pub mod auth;
+pub mod config;
+mod crypto;
 mod http;
+pub mod profile;
+pub mod settings;
+
+pub use config::{Preferences, WeekendDisplay};
+pub use crypto::PASSPHRASE_ENV;
+use profile::Profile;
 
 const SUBCOMMAND_NAME: &str = "bruker";
 
@@ -17,9 +33,110 @@ pub fn subcommand_app<'help>() -> App<'help> {
     App::new(SUBCOMMAND_NAME)
         .about("Brukerhåndtering")
         .setting(AppSettings::ArgRequiredElseHelp)
-        .subcommand(App::new("logg-inn").about("Logg inn i Floq"))
         .subcommand(
-            App::new("logg-ut").about("Logg ut av Floq (sletter din lokale brukerkonfigurasjon)"),
+            App::new("logg-inn")
+                .about("Logg inn i Floq")
+                .arg(
+                    Arg::new("uten-nettleser")
+                        .long("uten-nettleser")
+                        .about(
+                            "Logg inn uten en nettleser på denne maskinen, f.eks. over SSH.\nDu åpner lenken hvor som helst og limer inn adressen du blir sendt videre til.",
+                        ),
+                )
+                .arg(
+                    Arg::new("tidsavbrudd")
+                        .long("tidsavbrudd")
+                        .takes_value(true)
+                        .default_value("300")
+                        .conflicts_with("uten-nettleser")
+                        .about("Antall sekunder å vente på innloggingen i nettleseren før den avbrytes"),
+                ),
+        )
+        .subcommand(
+            App::new("logg-ut")
+                .about("Logg ut av Floq (ugyldiggjør innloggingen og sletter den lokalt)")
+                .arg(
+                    Arg::new("alle-profiler")
+                        .long("alle-profiler")
+                        .about("Logg ut av alle profiler, ikke bare den som er i bruk"),
+                ),
+        )
+        .subcommand(
+            App::new("status").about("Vis informasjon om den lagrede innloggingen, uten å logge inn"),
+        )
+        .subcommand(
+            App::new("krypter").about(
+                "Krypter den lagrede innloggingen med et passord (eller FLOQ_PASSPHRASE)",
+            ),
+        )
+        .subcommand(App::new("dekrypter").about("Lagre innloggingen ukryptert igjen"))
+        .subcommand(
+            App::new("profiler")
+                .about("Håndter profiler for ulike Floq-miljøer")
+                .setting(AppSettings::ArgRequiredElseHelp)
+                .subcommand(App::new("liste").about("Vis alle profiler"))
+                .subcommand(
+                    App::new("velg")
+                        .about("Velg profilen som skal brukes når --profil er utelatt")
+                        .arg(
+                            Arg::new("navn")
+                                .about("Navnet på profilen")
+                                .required(true)
+                                .index(1),
+                        ),
+                )
+                .subcommand(
+                    App::new("legg-til")
+                        .about("Legg til en ny profil")
+                        .arg(
+                            Arg::new("navn")
+                                .about("Navnet på profilen")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(
+                            Arg::new("domene")
+                                .long("domene")
+                                .takes_value(true)
+                                .required(true)
+                                .about("Domenet til Floq.\nF.eks. \"--domene https://blank-test.floq.no\""),
+                        )
+                        .arg(
+                            Arg::new("api-domene")
+                                .long("api-domene")
+                                .takes_value(true)
+                                .required(true)
+                                .about("Domenet til Floq API-et.\nF.eks. \"--api-domene https://api-blank-test.floq.no\""),
+                        )
+                        .arg(
+                            Arg::new("ca-sertifikater")
+                                .long("ca-sertifikater")
+                                .takes_value(true)
+                                .about("PEM-fil med CA-sertifikater profilen stoler på i tillegg til systemets, f.eks. på et nettverk som inspiserer TLS"),
+                        ),
+                )
+                .subcommand(
+                    App::new("ca-sertifikater")
+                        .about("Stol på CA-sertifikatene i en PEM-fil i tillegg til systemets når profilen brukes, f.eks. på et nettverk som inspiserer TLS")
+                        .arg(
+                            Arg::new("navn")
+                                .about("Navnet på profilen")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(
+                            Arg::new("fil")
+                                .about("PEM-filen med CA-sertifikatene")
+                                .required_unless_present("fjern")
+                                .index(2),
+                        )
+                        .arg(
+                            Arg::new("fjern")
+                                .long("fjern")
+                                .conflicts_with("fil")
+                                .about("Stol bare på systemets CA-sertifikater igjen"),
+                        ),
+                ),
         )
 }
 
@@ -37,45 +154,396 @@ impl<T: Write + Send> Subcommand<T> for UserSubcommand {
 
     async fn execute(&self, matches: &ArgMatches, out: &mut T) -> Result<()> {
         match matches.subcommand() {
-            Some(("logg-inn", _)) => {
-                authorize_user(out).await?;
+            Some(("logg-inn", login_matches)) => {
+                let config = config::load_config().await?;
+                let profile = profile::resolve(&config, matches.value_of("profil"))?;
+                let flow = if login_matches.is_present("uten-nettleser") {
+                    LoginFlow::Paste
+                } else {
+                    let timeout: u64 = login_matches
+                        .value_of("tidsavbrudd")
+                        .unwrap()
+                        .parse()
+                        .with_context(|| "Tidsavbruddet må være et antall sekunder, f.eks. 300")?;
+                    LoginFlow::Browser(std::time::Duration::from_secs(timeout))
+                };
+
+                authorize_user(&profile, flow, out).await?;
+                Ok(())
+            }
+            Some(("logg-ut", logout_matches)) => execute_logout(matches, logout_matches, out).await,
+            Some(("status", _)) => execute_status(matches, out).await,
+            Some(("krypter", _)) => {
+                let _lock = config::lock_config().await?;
+                let mut config = config::load_config().await?;
+
+                if env::var(crypto::PASSPHRASE_ENV).is_err() {
+                    let passphrase = rpassword::prompt_password("Nytt passord: ")?;
+                    if passphrase.is_empty() {
+                        return Err(anyhow!("Passordet kan ikke være tomt"));
+                    }
+                    if passphrase != rpassword::prompt_password("Gjenta passordet: ")? {
+                        return Err(anyhow!("Passordene er ikke like"));
+                    }
+                    crypto::set_passphrase(passphrase);
+                }
+
+                config.encrypt_credentials = true;
+                config::update_config(&config).await?;
+                writeln!(out, "Innloggingen er nå kryptert")?;
                 Ok(())
             }
-            Some(("logg-ut", _)) => {
-                config::delete_config().await?;
-                writeln!(out, "Ha det bra!")?;
+            Some(("dekrypter", _)) => {
+                let _lock = config::lock_config().await?;
+                let mut config = config::load_config().await?;
+
+                config.encrypt_credentials = false;
+                config::update_config(&config).await?;
+                writeln!(out, "Innloggingen er nå lagret ukryptert")?;
                 Ok(())
             }
+            Some(("profiler", sub_matches)) => execute_profiles(matches, sub_matches, out).await,
             _ => unreachable!("Unknown commands should be handled by the library"),
         }
     }
 }
 
+async fn execute_logout<T: Write + Send>(
+    matches: &ArgMatches,
+    logout_matches: &ArgMatches,
+    out: &mut T,
+) -> Result<()> {
+    let _lock = config::lock_config().await?;
+    let mut config = config::load_config().await?;
+    let profiles = if logout_matches.is_present("alle-profiler") {
+        profile::all(&config)
+    } else {
+        vec![profile::resolve(&config, matches.value_of("profil"))?]
+    };
+
+    for profile in profiles {
+        let credentials = match config.profiles.get_mut(&profile.name) {
+            Some(pc) => pc.credentials.take(),
+            None => None,
+        };
+        if let Some(c) = credentials {
+            // the local login is removed even if Floq can't be reached
+            if let Err(e) =
+                auth::revoke_refresh_token(&profile, &c.access_token, &c.refresh_token).await
+            {
+                eprintln!(
+                    "Advarsel: {:#}. Innloggingen er slettet lokalt, men kan fortsatt være gyldig hos Floq",
+                    e
+                );
+            }
+        }
+    }
+
+    config::update_config(&config).await?;
+    writeln!(out, "Ha det bra!")?;
+    Ok(())
+}
+
+async fn execute_status<T: Write + Send>(matches: &ArgMatches, out: &mut T) -> Result<()> {
+    let config = config::load_config().await?;
+    let profile = profile::resolve(&config, matches.value_of("profil"))?;
+    let now = Utc::now().naive_utc();
+
+    writeln!(
+        out,
+        "{:<21}{} ({})",
+        "Profil:", profile.name, profile.domain
+    )?;
+
+    let credentials = match config.credentials(&profile) {
+        Some(c) => c,
+        None => {
+            writeln!(out, "{:<21}nei", "Innlogget:")?;
+            return Ok(());
+        }
+    };
+
+    writeln!(
+        out,
+        "{:<21}{} (id {})",
+        "Ansatt:", credentials.name, credentials.employee_id
+    )?;
+    writeln!(out, "{:<21}{}", "E-post:", credentials.email)?;
+    writeln!(
+        out,
+        "{:<21}{}",
+        "Token utløper:",
+        ExpiryDate(&credentials.access_token_expires, &now)
+    )?;
+
+    match auth::decode_access_token(&credentials.access_token) {
+        Ok(claims) => {
+            if let Some(exp) = claims
+                .registered
+                .expiration
+                .and_then(|exp| NaiveDateTime::from_timestamp_opt(exp as i64, 0))
+            {
+                writeln!(
+                    out,
+                    "{:<21}{}",
+                    "Token utløper (JWT):",
+                    ExpiryDate(&exp, &now)
+                )?;
+            }
+            if let Some(email) = claims.private.get("email").and_then(|e| e.as_str()) {
+                writeln!(out, "{:<21}{}", "E-post (JWT):", email)?;
+            }
+            if let Some(role) = claims.private.get("role").and_then(|r| r.as_str()) {
+                writeln!(out, "{:<21}{}", "Rolle (JWT):", role)?;
+            }
+        }
+        Err(e) => writeln!(out, "{:<21}{}", "Token:", e)?,
+    }
+
+    writeln!(
+        out,
+        "{:<21}{}",
+        "Refresh-token:",
+        if credentials.refresh_token.is_empty() {
+            "mangler"
+        } else {
+            "ja"
+        }
+    )?;
+    writeln!(
+        out,
+        "{:<21}{}",
+        "Trenger fornyelse:",
+        if credentials.needs_refresh(now) {
+            "ja"
+        } else {
+            "nei"
+        }
+    )?;
+
+    Ok(())
+}
+
+struct ExpiryDate<'a>(&'a NaiveDateTime, &'a NaiveDateTime);
+
+impl<'a> Display for ExpiryDate<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let remaining = *self.0 - *self.1;
+        let amount = if remaining < Duration::zero() {
+            -remaining
+        } else {
+            remaining
+        };
+        let amount = if amount.num_hours() >= 48 {
+            format!("{} dager", amount.num_days())
+        } else if amount.num_minutes() >= 120 {
+            format!("{} timer", amount.num_hours())
+        } else {
+            format!("{} minutter", amount.num_minutes())
+        };
+
+        write!(f, "{} UTC ", self.0.format("%Y-%m-%d %H:%M:%S"))?;
+        if remaining < Duration::zero() {
+            write!(f, "(utløpt for {} siden)", amount)
+        } else {
+            write!(f, "(om {})", amount)
+        }
+    }
+}
+
+struct ProfileRow {
+    active: bool,
+    profile: Profile,
+    logged_in: bool,
+}
+
+async fn execute_profiles<T: Write + Send>(
+    matches: &ArgMatches,
+    sub_matches: &ArgMatches,
+    out: &mut T,
+) -> Result<()> {
+    let _lock = config::lock_config().await?;
+    let mut config = config::load_config().await?;
+
+    match sub_matches.subcommand() {
+        Some(("liste", _)) => {
+            let active = profile::active_name(&config, matches.value_of("profil"));
+            let rows: Vec<ProfileRow> = profile::all(&config)
+                .into_iter()
+                .map(|p| ProfileRow {
+                    active: p.name == active,
+                    logged_in: config.credentials(&p).is_some(),
+                    profile: p,
+                })
+                .collect();
+
+            let mut table_maker = TableMaker::new();
+            table_maker.style(config.preferences.table_style);
+            table_maker.static_titles(vec!["", "PROFIL", "DOMENE", "API-DOMENE", "INNLOGGET"]);
+            table_maker
+                .with(Box::new(|r: &ProfileRow| {
+                    if r.active { "*" } else { "" }.to_string()
+                }))
+                .with(Box::new(|r| r.profile.name.clone()))
+                .with(Box::new(|r| r.profile.domain.clone()))
+                .with(Box::new(|r| r.profile.api_domain.clone()))
+                .with(Box::new(|r| {
+                    if r.logged_in { "ja" } else { "nei" }.to_string()
+                }));
+            table_maker.into_table(&rows).print(out)?;
+            Ok(())
+        }
+        Some(("velg", velg_matches)) => {
+            let name = velg_matches.value_of("navn").unwrap();
+            let profile = profile::find(&config, name)
+                .ok_or_else(|| anyhow!("Fant ingen profil med navnet \"{}\"", name))?;
+
+            config.active_profile = Some(profile.name.clone());
+            config::update_config(&config).await?;
+            writeln!(
+                out,
+                "Bruker nå profilen {} ({})",
+                profile.name, profile.domain
+            )?;
+            Ok(())
+        }
+        Some(("legg-til", add_matches)) => {
+            let name = add_matches.value_of("navn").unwrap();
+            if profile::find(&config, name).is_some() {
+                return Err(anyhow!(
+                    "Det finnes allerede en profil med navnet \"{}\"",
+                    name
+                ));
+            }
+
+            let profile = Profile {
+                name: name.to_string(),
+                domain: add_matches
+                    .value_of("domene")
+                    .unwrap()
+                    .trim_end_matches('/')
+                    .to_string(),
+                api_domain: add_matches
+                    .value_of("api-domene")
+                    .unwrap()
+                    .trim_end_matches('/')
+                    .to_string(),
+                ca_certificates: add_matches
+                    .value_of("ca-sertifikater")
+                    .map(absolute_path)
+                    .transpose()?,
+            };
+            config.profile_mut(&profile).ca_certificates = profile.ca_certificates.clone();
+            config::update_config(&config).await?;
+            writeln!(out, "La til profilen {}", profile.name)?;
+            Ok(())
+        }
+        Some(("ca-sertifikater", ca_matches)) => {
+            let name = ca_matches.value_of("navn").unwrap();
+            let profile = profile::find(&config, name)
+                .ok_or_else(|| anyhow!("Fant ingen profil med navnet \"{}\"", name))?;
+            let ca_certificates = ca_matches.value_of("fil").map(absolute_path).transpose()?;
+
+            config.profile_mut(&profile).ca_certificates = ca_certificates.clone();
+            config::update_config(&config).await?;
+            match ca_certificates {
+                Some(path) => writeln!(
+                    out,
+                    "Profilen {} stoler nå på CA-sertifikatene i {}",
+                    profile.name,
+                    path.display()
+                )?,
+                None => writeln!(
+                    out,
+                    "Profilen {} stoler nå bare på systemets CA-sertifikater",
+                    profile.name
+                )?,
+            }
+            Ok(())
+        }
+        _ => unreachable!("Unknown commands should be handled by the library"),
+    }
+}
+
+/// The path as stored in the config, which must not depend on where floq is run from.
+fn absolute_path(path: &str) -> Result<PathBuf> {
+    std::fs::canonicalize(path)
+        .with_context(|| FloqError::Validation(format!("Fant ikke filen {}", path)))
+}
+
+/// The logged in employee, with the tokens used to authenticate as them.
 pub struct User {
     pub employee_id: u16,
     pub email: String,
     pub name: String,
     pub access_token: String,
+    pub refresh_token: Option<String>,
+    pub profile: Profile,
+    pub source: CredentialSource,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CredentialSource {
+    /// Logged in with `bruker logg-inn`, refreshed tokens are written back to the config.
+    Config,
+    /// Tokens from `FLOQ_ACCESS_TOKEN` or `FLOQ_TOKEN_FILE`, which are never written to disk.
+    Environment,
+}
+
+/// Adds [`FloqError::NotAuthenticated`] to an error from refreshing the login, unless Floq could
+/// not be reached or failed, in which case logging in again would not help.
+pub(crate) fn login_required(e: anyhow::Error, profile: &Profile) -> anyhow::Error {
+    match e.downcast_ref::<FloqError>() {
+        Some(FloqError::Network) | Some(FloqError::Server(_)) => e,
+        _ => e.context(FloqError::NotAuthenticated {
+            profile: profile.name.clone(),
+        }),
+    }
 }
+
+/// An employee as returned by Floq.
+#[derive(Debug, Clone, PartialEq)]
 pub struct Employee {
-    id: u16,
-    email: String,
-    name: String,
+    pub id: u16,
+    pub email: String,
+    pub name: String,
+}
+
+pub enum LoginFlow {
+    /// The browser is redirected to a callback server on this machine, which waits for at most the
+    /// given duration.
+    Browser(std::time::Duration),
+    /// The user pastes the URL the browser was redirected to.
+    Paste,
 }
 
-pub async fn authorize_user<OUT: Write + Send>(out: &mut OUT) -> Result<User> {
-    let authorized_user = auth::authorize(out).await?;
+pub async fn authorize_user<OUT: Write + Send>(
+    profile: &Profile,
+    flow: LoginFlow,
+    out: &mut OUT,
+) -> Result<User> {
+    // asked for first, as the tokens would be lost if they couldn't be encrypted after the login
+    if config::read_config().await?.encrypt_credentials {
+        crypto::passphrase()?;
+    }
+
+    let authorized_user = match flow {
+        LoginFlow::Browser(timeout) => auth::authorize(out, profile, timeout).await?,
+        LoginFlow::Paste => auth::authorize_headless(out, &mut io::stdin().lock(), profile).await?,
+    };
 
-    let employee = http::get_logged_in_employee(&authorized_user.access_token).await?;
+    let employee = http::get_logged_in_employee(profile, &authorized_user.access_token).await?;
 
-    let config = config::UserConfig {
+    let _lock = config::lock_config().await?;
+    let mut config = config::load_config().await?;
+    config.profile_mut(profile).credentials = Some(config::Credentials {
         employee_id: employee.id,
         email: employee.email.clone(),
         name: employee.name.clone(),
         access_token: authorized_user.access_token.clone(),
         access_token_expires: authorized_user.expires_at,
-        refresh_token: authorized_user.refresh_token,
-    };
+        refresh_token: authorized_user.refresh_token.clone(),
+    });
     config::update_config(&config).await?;
 
     writeln!(out, "Hei, {}!", employee.name)?;
@@ -86,41 +554,204 @@ pub async fn authorize_user<OUT: Write + Send>(out: &mut OUT) -> Result<User> {
         email: employee.email,
         name: employee.name,
         access_token: authorized_user.access_token,
+        refresh_token: Some(authorized_user.refresh_token),
+        profile: profile.clone(),
+        source: CredentialSource::Config,
     })
 }
 
-pub async fn load_user_from_config<OUT: Write + Send>(out: &mut OUT) -> Result<User> {
+/// Loads the user of the profile selected with `--profil`, see [`load_user`], and the preferences
+/// from the same read of the config.
+pub async fn load_user_and_preferences<OUT: Write + Send>(
+    matches: &ArgMatches,
+    out: &mut OUT,
+) -> Result<(User, Preferences)> {
     let config = config::load_config().await?;
+    let preferences = config.preferences.clone();
+    let user = user_from_config(
+        config,
+        matches.value_of("profil"),
+        cmd::is_interactive(matches),
+        out,
+    )
+    .await?;
+
+    Ok((user, preferences))
+}
+
+/// Loads the logged in user of the selected profile, or the active profile if `None`. Tokens from
+/// the environment are preferred over those stored in the config, which are refreshed if needed.
+///
+/// If there is no login a new one is started, writing the instructions to `out`, unless
+/// `interactive` is false in which case a [`FloqError::NotAuthenticated`] error is returned.
+pub async fn load_user<OUT: Write + Send>(
+    selected_profile: Option<&str>,
+    interactive: bool,
+    out: &mut OUT,
+) -> Result<User> {
+    user_from_config(
+        config::load_config().await?,
+        selected_profile,
+        interactive,
+        out,
+    )
+    .await
+}
+
+async fn user_from_config<OUT: Write + Send>(
+    mut config: config::UserConfig,
+    selected_profile: Option<&str>,
+    interactive: bool,
+    out: &mut OUT,
+) -> Result<User> {
+    let profile = profile::resolve(&config, selected_profile)?;
     let now = Utc::now().naive_utc();
 
-    match config {
+    if let Some(tokens) = auth::service_tokens_from_env().await? {
+        return load_user_from_service_tokens(profile, tokens)
+            .await
+            .with_context(|| {
+                format!(
+                    "Klarte ikke å logge inn med tokenet fra {} eller {}",
+                    auth::ACCESS_TOKEN_ENV,
+                    auth::TOKEN_FILE_ENV
+                )
+            });
+    }
+
+    match config.profile_mut(&profile).credentials.as_mut() {
+        None if !interactive => Err(anyhow!(FloqError::NotAuthenticated {
+            profile: profile.name
+        })),
         None => {
             writeln!(
                 out,
-                "Fant ingen konfigurasjon så starter løpet for autentisering nå:"
+                "Fant ingen konfigurasjon for profilen {} så starter løpet for autentisering nå:",
+                profile.name
             )?;
-            authorize_user(out).await
+            authorize_user(
+                &profile,
+                LoginFlow::Browser(auth::DEFAULT_LOGIN_TIMEOUT),
+                out,
+            )
+            .await
         }
-        Some(mut c) if c.access_token_expires < now - Duration::minutes(1) => {
-            let authorized_user = auth::refresh_access_token(&c.refresh_token).await?;
-
-            c.access_token = authorized_user.access_token;
-            c.access_token_expires = authorized_user.expires_at;
-
-            config::update_config(&c).await?;
+        Some(c) if c.needs_refresh(now) => {
+            let c = refresh_stored_credentials(&profile, |c| c.needs_refresh(now))
+                .await
+                .map_err(|e| login_required(e, &profile))?;
 
             Ok(User {
                 employee_id: c.employee_id,
                 email: c.email,
                 name: c.name,
                 access_token: c.access_token,
+                refresh_token: Some(c.refresh_token),
+                profile,
+                source: CredentialSource::Config,
             })
         }
         Some(c) => Ok(User {
             employee_id: c.employee_id,
-            email: c.email,
-            name: c.name,
-            access_token: c.access_token,
+            email: c.email.clone(),
+            name: c.name.clone(),
+            access_token: c.access_token.clone(),
+            refresh_token: Some(c.refresh_token.clone()),
+            profile,
+            source: CredentialSource::Config,
         }),
     }
 }
+
+async fn load_user_from_service_tokens(
+    profile: Profile,
+    tokens: auth::ServiceTokens,
+) -> Result<User> {
+    let auth::ServiceTokens {
+        mut access_token,
+        refresh_token,
+    } = tokens;
+
+    let employee = match (
+        http::get_logged_in_employee(&profile, &access_token).await,
+        &refresh_token,
+    ) {
+        (Ok(employee), _) => employee,
+        // the access token might have expired, so try again with a fresh one
+        (Err(e), Some(rt)) => {
+            let refreshed =
+                refresh_access_token(&profile, &access_token, rt, CredentialSource::Environment)
+                    .await;
+            access_token = refreshed
+                .with_context(|| format!("Tokenet ble avvist ({:#}), og kunne ikke fornyes", e))?;
+            http::get_logged_in_employee(&profile, &access_token).await?
+        }
+        (Err(e), None) => return Err(e),
+    };
+
+    Ok(User {
+        employee_id: employee.id,
+        email: employee.email,
+        name: employee.name,
+        access_token,
+        refresh_token,
+        profile,
+        source: CredentialSource::Environment,
+    })
+}
+
+/// Refreshes the rejected access token of the profile. Tokens from the config are refreshed with
+/// the stored refresh token, which might have been rotated by another floq command, and the new
+/// tokens are stored.
+pub async fn refresh_access_token(
+    profile: &Profile,
+    rejected_access_token: &str,
+    refresh_token: &str,
+    source: CredentialSource,
+) -> Result<String> {
+    match source {
+        CredentialSource::Environment => auth::refresh_access_token(profile, refresh_token)
+            .await
+            .map(|au| au.access_token),
+        CredentialSource::Config => {
+            refresh_stored_credentials(profile, |c| c.access_token == rejected_access_token)
+                .await
+                .map(|c| c.access_token)
+        }
+    }
+}
+
+/// Refreshes the stored credentials of the profile if `needs_refresh` still holds once the config
+/// is locked, as another floq command might have refreshed them while we waited for the lock.
+async fn refresh_stored_credentials<F>(
+    profile: &Profile,
+    needs_refresh: F,
+) -> Result<config::Credentials>
+where
+    F: Fn(&config::Credentials) -> bool,
+{
+    let _lock = config::lock_config().await?;
+    let mut config = config::load_config().await?;
+
+    let credentials = match config.profile_mut(profile).credentials.as_mut() {
+        Some(c) => c,
+        None => {
+            return Err(anyhow!(FloqError::NotAuthenticated {
+                profile: profile.name.clone()
+            }))
+        }
+    };
+    if !needs_refresh(credentials) {
+        return Ok(credentials.clone());
+    }
+
+    let authorized_user = auth::refresh_access_token(profile, &credentials.refresh_token).await?;
+    credentials.access_token = authorized_user.access_token;
+    credentials.access_token_expires = authorized_user.expires_at;
+    credentials.refresh_token = authorized_user.refresh_token;
+    let refreshed = credentials.clone();
+
+    config::update_config(&config).await?;
+
+    Ok(refreshed)
+}
@@ -0,0 +1,97 @@
+use super::config::{ProfileConfig, UserConfig};
+
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+
+pub const DEFAULT_PROFILE: &str = match option_env!("FLOQ_DEFAULT_PROFILE") {
+    Some(p) => p,
+    None => "blank-test",
+};
+
+const BUILTIN_PROFILES: [(&str, &str, &str); 3] = [
+    (
+        "blank-test",
+        "https://blank-test.floq.no",
+        "https://api-blank-test.floq.no",
+    ),
+    (
+        "blank",
+        "https://inni.blank.no",
+        "https://api-blank.floq.no",
+    ),
+    ("folq", "https://folq.floq.no", "https://api-folq.floq.no"),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    pub name: String,
+    pub domain: String,
+    pub api_domain: String,
+    /// CA certificates trusted in addition to the system's when connecting.
+    pub ca_certificates: Option<PathBuf>,
+}
+
+impl Profile {
+    fn from_config(name: &str, config: &ProfileConfig) -> Self {
+        Profile {
+            name: name.to_string(),
+            domain: config.domain.clone(),
+            api_domain: config.api_domain.clone(),
+            ca_certificates: config.ca_certificates.clone(),
+        }
+    }
+}
+
+fn builtin(name: &str) -> Option<Profile> {
+    BUILTIN_PROFILES
+        .iter()
+        .find(|(n, _, _)| *n == name)
+        .map(|(name, domain, api_domain)| Profile {
+            name: name.to_string(),
+            domain: domain.to_string(),
+            api_domain: api_domain.to_string(),
+            ca_certificates: None,
+        })
+}
+
+/// Name of the profile selected by `--profil`/`FLOQ_PROFILE`, falling back to the active profile
+/// in the config and lastly the default profile.
+pub fn active_name<'a>(config: &'a UserConfig, selected: Option<&'a str>) -> &'a str {
+    selected
+        .or(config.active_profile.as_deref())
+        .unwrap_or(DEFAULT_PROFILE)
+}
+
+pub fn find(config: &UserConfig, name: &str) -> Option<Profile> {
+    config
+        .profiles
+        .get(name)
+        .map(|pc| Profile::from_config(name, pc))
+        .or_else(|| builtin(name))
+}
+
+pub fn resolve(config: &UserConfig, selected: Option<&str>) -> Result<Profile> {
+    let name = active_name(config, selected);
+
+    find(config, name).ok_or_else(|| {
+        anyhow!(
+            "Fant ingen profil med navnet \"{}\", se \"floq bruker profiler liste\"",
+            name
+        )
+    })
+}
+
+/// All known profiles, the built-in ones first followed by those added by the user.
+pub fn all(config: &UserConfig) -> Vec<Profile> {
+    let builtins = BUILTIN_PROFILES
+        .iter()
+        .filter_map(|(name, _, _)| find(config, name));
+    let custom = config
+        .profiles
+        .iter()
+        .filter(|(name, _)| builtin(name).is_none())
+        .map(|(name, pc)| Profile::from_config(name, pc));
+
+    builtins.chain(custom).collect()
+}
@@ -0,0 +1,427 @@
+use super::config::{self, UserConfig};
+use crate::{
+    cmd::{self, Subcommand},
+    error::FloqError,
+    trace::HIDDEN_SECRET,
+};
+
+use std::{
+    env,
+    io::{self, BufRead, Write},
+    path::Path,
+    process::Command,
+};
+
+use anyhow::{anyhow, Context, Result};
+use async_std::fs;
+use async_trait::async_trait;
+use clap::{App, AppSettings, Arg, ArgMatches};
+use toml::{value::Table, Value};
+
+const SUBCOMMAND_NAME: &str = "konfig";
+
+/// Keys whose values are never printed without `--vis-hemmeligheter`.
+const SECRET_KEYS: [&str; 3] = ["access_token", "refresh_token", "encrypted_credentials"];
+
+pub fn subcommand_app<'help>() -> App<'help> {
+    let key_arg = Arg::new("nøkkel")
+        .about(
+            "Nøkkelen i konfigurasjonen, med punktum mellom nivåene.\nF.eks. \"preferanser.timer\"",
+        )
+        .required(true)
+        .index(1);
+    let show_secrets_arg = Arg::new("vis-hemmeligheter")
+        .long("vis-hemmeligheter")
+        .about("Vis også tokens, som ellers er skjult");
+
+    App::new(SUBCOMMAND_NAME)
+        .about("Vis og endre konfigurasjonen")
+        .setting(AppSettings::ArgRequiredElseHelp)
+        .subcommand(
+            App::new("hent")
+                .about("Vis verdien til en nøkkel")
+                .arg(key_arg.clone())
+                .arg(show_secrets_arg.clone()),
+        )
+        .subcommand(
+            App::new("sett")
+                .about("Sett verdien til en nøkkel")
+                // so that e.g. negative hours get the same message as other invalid values
+                .setting(AppSettings::AllowNegativeNumbers)
+                .arg(key_arg.clone())
+                .arg(
+                    Arg::new("verdi")
+                        .about("Den nye verdien, i TOML-format for tall og sannhetsverdier.\nF.eks. \"7.5\" eller \"PRO1000\"")
+                        .required(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            App::new("liste")
+                .about("Vis hele konfigurasjonen")
+                .arg(show_secrets_arg),
+        )
+        .subcommand(
+            App::new("fjern")
+                .about("Fjern en nøkkel, slik at standardverdien brukes")
+                .arg(key_arg),
+        )
+        .subcommand(App::new("rediger").about(
+            "Rediger konfigurasjonsfilen med $VISUAL eller $EDITOR, endringene lagres bare hvis de er gyldige",
+        ))
+}
+
+pub fn subcommand<T: Write + Send>() -> Box<dyn Subcommand<T>> {
+    Box::new(SettingsSubcommand)
+}
+
+struct SettingsSubcommand;
+
+#[async_trait(?Send)]
+impl<T: Write + Send> Subcommand<T> for SettingsSubcommand {
+    fn matches(&self, matches: &ArgMatches) -> bool {
+        matches.subcommand_name() == Some(SUBCOMMAND_NAME)
+    }
+
+    async fn execute(&self, matches: &ArgMatches, out: &mut T) -> Result<()> {
+        match matches.subcommand() {
+            Some(("hent", get_matches)) => {
+                let key = get_matches.value_of("nøkkel").unwrap();
+                let show_secrets = get_matches.is_present("vis-hemmeligheter");
+                if is_secret(key) && !show_secrets {
+                    return Err(anyhow!(FloqError::Validation(format!(
+                        "{} er hemmelig, bruk --vis-hemmeligheter for å vise den",
+                        key
+                    ))));
+                }
+
+                let mut value = to_value(&load(show_secrets).await?)?;
+                let value = lookup(&mut value, key).ok_or_else(|| no_value(key))?;
+                if !show_secrets {
+                    hide_secrets(value);
+                }
+
+                match value {
+                    Value::String(s) => writeln!(out, "{}", s)?,
+                    Value::Table(t) => write!(out, "{}", toml::to_string(t)?)?,
+                    v => writeln!(out, "{}", v)?,
+                }
+                Ok(())
+            }
+            Some(("sett", set_matches)) => {
+                let key = set_matches.value_of("nøkkel").unwrap();
+                let new_value = parse_value(set_matches.value_of("verdi").unwrap());
+
+                let _lock = config::lock_config().await?;
+                let mut value = to_value(&load(is_secret_path(key)).await?)?;
+                let (parent, last) = split_key(key)?;
+                match lookup_or_create_table(&mut value, parent)? {
+                    Value::Table(t) => {
+                        t.insert(last.to_string(), new_value);
+                    }
+                    _ => return Err(not_a_table(parent)),
+                }
+
+                let config = config::from_value(value).with_context(|| {
+                    FloqError::Validation(format!("Ugyldig nøkkel eller verdi for {}", key))
+                })?;
+                config::update_config(&config).await?;
+                writeln!(out, "Satte {}", key)?;
+                Ok(())
+            }
+            Some(("liste", list_matches)) => {
+                let show_secrets = list_matches.is_present("vis-hemmeligheter");
+                let mut value = to_value(&load(show_secrets).await?)?;
+                if !show_secrets {
+                    hide_secrets(&mut value);
+                }
+
+                let mut lines = vec![];
+                flatten("", &value, &mut lines);
+                for (key, value) in lines {
+                    writeln!(out, "{} = {}", key, value)?;
+                }
+                Ok(())
+            }
+            Some(("fjern", remove_matches)) => {
+                let key = remove_matches.value_of("nøkkel").unwrap();
+
+                let _lock = config::lock_config().await?;
+                let mut value = to_value(&load(is_secret_path(key)).await?)?;
+                let (parent, last) = split_key(key)?;
+                let removed = match lookup(&mut value, parent) {
+                    Some(Value::Table(t)) => t.remove(last),
+                    _ => None,
+                };
+                if removed.is_none() {
+                    return Err(no_value(key));
+                }
+
+                let config = config::from_value(value).with_context(|| {
+                    FloqError::Validation(format!("{} kan ikke fjernes fra konfigurasjonen", key))
+                })?;
+                config::update_config(&config).await?;
+                writeln!(out, "Fjernet {}", key)?;
+                Ok(())
+            }
+            Some(("rediger", _)) => execute_edit(matches, out).await,
+            _ => unreachable!("Unknown commands should be handled by the library"),
+        }
+    }
+}
+
+/// The credentials are only decrypted when they are needed, to avoid asking for the passphrase.
+async fn load(decrypt: bool) -> Result<UserConfig> {
+    if decrypt {
+        config::load_config().await
+    } else {
+        config::read_config().await
+    }
+}
+
+/// The config as TOML, with every preference included so that the defaults can be shown too.
+fn to_value(config: &UserConfig) -> Result<Value> {
+    let mut value =
+        Value::try_from(config).with_context(|| "Klarte ikke å lese konfigurasjonen")?;
+    if let Value::Table(t) = &mut value {
+        let preferences = Value::try_from(&config.preferences)
+            .with_context(|| "Klarte ikke å lese preferansene")?;
+        t.insert("preferanser".to_string(), preferences);
+    }
+
+    Ok(value)
+}
+
+fn no_value(key: &str) -> anyhow::Error {
+    anyhow!(FloqError::Validation(format!(
+        "Fant ingen verdi for {}",
+        key
+    )))
+}
+
+fn not_a_table(key: &str) -> anyhow::Error {
+    anyhow!(FloqError::Validation(format!(
+        "{} er ikke en tabell i konfigurasjonen",
+        key
+    )))
+}
+
+fn is_secret(key: &str) -> bool {
+    key.rsplit('.')
+        .next()
+        .map(|last| SECRET_KEYS.contains(&last))
+        .unwrap_or(false)
+}
+
+/// Whether the key is, or is part of, the credentials.
+fn is_secret_path(key: &str) -> bool {
+    is_secret(key) || key.split('.').any(|k| k == "credentials")
+}
+
+fn hide_secrets(value: &mut Value) {
+    if let Value::Table(t) = value {
+        for (key, v) in t.iter_mut() {
+            if SECRET_KEYS.contains(&key.as_str()) {
+                *v = Value::String(HIDDEN_SECRET.to_string());
+            } else {
+                hide_secrets(v);
+            }
+        }
+    }
+}
+
+fn split_key(key: &str) -> Result<(&str, &str)> {
+    match key.rsplit_once('.') {
+        Some((parent, last)) if !last.is_empty() => Ok((parent, last)),
+        None if !key.is_empty() => Ok(("", key)),
+        _ => Err(anyhow!(FloqError::Validation(format!(
+            "Ugyldig nøkkel {}",
+            key
+        )))),
+    }
+}
+
+fn lookup<'a>(value: &'a mut Value, key: &str) -> Option<&'a mut Value> {
+    key.split('.')
+        .filter(|k| !k.is_empty())
+        .try_fold(value, |v, k| v.get_mut(k))
+}
+
+fn lookup_or_create_table<'a>(value: &'a mut Value, key: &str) -> Result<&'a mut Value> {
+    key.split('.')
+        .filter(|k| !k.is_empty())
+        .try_fold(value, |v, k| match v {
+            Value::Table(t) => Ok(t
+                .entry(k.to_string())
+                .or_insert_with(|| Value::Table(Table::new()))),
+            _ => Err(not_a_table(key)),
+        })
+}
+
+/// Values that aren't valid TOML, such as project ids, are read as strings.
+fn parse_value(value: &str) -> Value {
+    toml::from_str::<Table>(&format!("verdi = {}", value))
+        .ok()
+        .and_then(|mut t| t.remove("verdi"))
+        .unwrap_or_else(|| Value::String(value.to_string()))
+}
+
+fn flatten(prefix: &str, value: &Value, lines: &mut Vec<(String, String)>) {
+    match value {
+        Value::Table(t) => {
+            for (key, v) in t {
+                let key = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten(&key, v, lines);
+            }
+        }
+        v => lines.push((prefix.to_string(), v.to_string())),
+    }
+}
+
+async fn execute_edit<T: Write + Send>(matches: &ArgMatches, out: &mut T) -> Result<()> {
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .map_err(|_| anyhow!("Fant ingen editor, sett $VISUAL eller $EDITOR"))?;
+
+    // the credentials stay encrypted while the file is edited
+    let original = toml::to_string(&config::read_config().await?)
+        .with_context(|| "Klarte ikke å lese konfigurasjonen")?;
+    let edit_path = config::file_path()?.with_extension("rediger.toml");
+    config::write_private(&edit_path, original.as_bytes())
+        .await
+        .with_context(|| format!("Klarte ikke å skrive til {}", edit_path.display()))?;
+
+    let result = edit_until_valid(matches, out, &editor, &edit_path, &original).await;
+
+    let _ = fs::remove_file(&edit_path).await;
+    result
+}
+
+/// Opens the editor again as long as the user wants to fix an invalid config.
+async fn edit_until_valid<T: Write + Send>(
+    matches: &ArgMatches,
+    out: &mut T,
+    editor: &str,
+    edit_path: &Path,
+    original: &str,
+) -> Result<()> {
+    loop {
+        let mut parts = editor.split_whitespace();
+        let status = Command::new(parts.next().unwrap_or(editor))
+            .args(parts)
+            .arg(edit_path)
+            .status()
+            .with_context(|| format!("Klarte ikke å starte editoren {}", editor))?;
+        if !status.success() {
+            return Err(anyhow!(
+                "Editoren avsluttet med {}, endringene er ikke lagret",
+                status
+            ));
+        }
+
+        let edited = fs::read_to_string(edit_path)
+            .await
+            .with_context(|| format!("Klarte ikke å lese {}", edit_path.display()))?;
+        if edited == original {
+            writeln!(out, "Ingen endringer")?;
+            return Ok(());
+        }
+
+        let config = toml::from_str(&edited)
+            .with_context(|| "Konfigurasjonen er ikke gyldig TOML")
+            .and_then(config::from_value);
+        match config {
+            Ok(config) => {
+                // the config isn't locked while it's edited, so it's only saved if no other floq
+                // command changed it in the meantime
+                let _lock = config::lock_config().await?;
+                if toml::to_string(&config::read_config().await?)? != original {
+                    return Err(anyhow!(
+                        "Konfigurasjonen ble endret av en annen floq-kommando mens den ble redigert, endringene er ikke lagret"
+                    ));
+                }
+                config::update_config(&config).await?;
+                writeln!(out, "Lagret konfigurasjonen")?;
+                return Ok(());
+            }
+            Err(e) if cmd::is_interactive(matches) => {
+                write!(out, "{:#}\nVil du rette opp i konfigurasjonen? [J/n] ", e)?;
+                out.flush()?;
+
+                let mut answer = String::new();
+                io::stdin().lock().read_line(&mut answer)?;
+                if answer.trim().eq_ignore_ascii_case("n") {
+                    return Err(anyhow!("Endringene er ikke lagret"));
+                }
+            }
+            Err(e) => return Err(e.context("Endringene er ikke lagret")),
+        }
+    }
+}
+
+#[cfg(test)]
+demonstrate::demonstrate! {
+    describe "parse_value" {
+        use super::*;
+
+        it "parses TOML values and reads anything else as a string" {
+            assert_eq!(parse_value("7.5"), Value::Float(7.5));
+            assert_eq!(parse_value("true"), Value::Boolean(true));
+            assert_eq!(parse_value("\"PRO1000\""), Value::String("PRO1000".to_string()));
+            assert_eq!(parse_value("PRO1000"), Value::String("PRO1000".to_string()));
+            assert_eq!(parse_value("2 uker"), Value::String("2 uker".to_string()));
+        }
+    }
+
+    describe "lookup and flatten" {
+        use super::*;
+
+        before {
+            let value: Value = toml::from_str(
+                r#"
+                active_profile = "test"
+
+                [preferanser]
+                timer = 7.5
+
+                [profiles.test]
+                domain = "https://blank-test.floq.no"
+                "#,
+            )
+            .unwrap();
+        }
+
+        it "looks up nested keys" {
+            let mut value = value;
+
+            assert_eq!(lookup(&mut value, "preferanser.timer"), Some(&mut Value::Float(7.5)));
+            assert_eq!(
+                lookup(&mut value, "profiles.test.domain").and_then(|v| v.as_str().map(String::from)),
+                Some("https://blank-test.floq.no".to_string())
+            );
+            assert_eq!(lookup(&mut value, "preferanser.tull"), None);
+            assert_eq!(lookup(&mut value, "active_profile.tull"), None);
+        }
+
+        it "flattens the tables into dotted keys" {
+            let mut lines = vec![];
+            flatten("", &value, &mut lines);
+
+            assert_eq!(
+                lines,
+                vec![
+                    ("active_profile".to_string(), "\"test\"".to_string()),
+                    ("preferanser.timer".to_string(), "7.5".to_string()),
+                    (
+                        "profiles.test.domain".to_string(),
+                        "\"https://blank-test.floq.no\"".to_string()
+                    ),
+                ]
+            );
+        }
+    }
+}